@@ -0,0 +1,177 @@
+use async_trait::async_trait;
+use common::events::{
+    dto::{CreatedBook, DeletedBook, UpdatedBook},
+    envelope::EventEnvelope,
+};
+use kafka::handler::MessageHandler;
+use std::collections::{HashSet, VecDeque};
+use std::convert::Infallible;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use tracing::{debug, info};
+
+const RECENT_EVENT_CAPACITY: usize = 10_000;
+
+/// Handles each book event, keyed by book id, once, skipping redeliveries whose event id was
+/// among the most recently handled on any of the book topics.
+pub struct BookEventsHandler {
+    recent_events: Mutex<RecentEvents>,
+}
+
+impl BookEventsHandler {
+    pub fn new() -> Self {
+        Self {
+            recent_events: Mutex::new(RecentEvents::new(RECENT_EVENT_CAPACITY)),
+        }
+    }
+
+    fn record<T: Debug>(&self, book_id: i32, event: &EventEnvelope<T>) {
+        if !self
+            .recent_events
+            .lock()
+            .expect("recent events lock poisoned")
+            .insert(&event.event_id)
+        {
+            debug!("Skipping duplicate event {}", event.event_id);
+            return;
+        }
+        info!(
+            "Consumed event {} for book {} from {} at {} (correlation id {:?}): {:?}",
+            event.event_id,
+            book_id,
+            event.producer,
+            event.occurred_at,
+            event.correlation_id,
+            event.payload
+        );
+    }
+}
+
+impl Default for BookEventsHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl MessageHandler<(i32, EventEnvelope<CreatedBook>)> for BookEventsHandler {
+    type Error = Infallible;
+
+    async fn handle(
+        &self,
+        (book_id, event): (i32, EventEnvelope<CreatedBook>),
+    ) -> Result<(), Self::Error> {
+        self.record(book_id, &event);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageHandler<(i32, EventEnvelope<UpdatedBook>)> for BookEventsHandler {
+    type Error = Infallible;
+
+    async fn handle(
+        &self,
+        (book_id, event): (i32, EventEnvelope<UpdatedBook>),
+    ) -> Result<(), Self::Error> {
+        self.record(book_id, &event);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageHandler<(i32, EventEnvelope<DeletedBook>)> for BookEventsHandler {
+    type Error = Infallible;
+
+    async fn handle(
+        &self,
+        (book_id, event): (i32, EventEnvelope<DeletedBook>),
+    ) -> Result<(), Self::Error> {
+        self.record(book_id, &event);
+        Ok(())
+    }
+}
+
+/// Bounded set of event ids, forgetting the oldest once full.
+struct RecentEvents {
+    capacity: usize,
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl RecentEvents {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            ids: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Returns `false` when the id has already been seen.
+    fn insert(&mut self, event_id: &str) -> bool {
+        if self.ids.contains(event_id) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.order.push_back(event_id.to_owned());
+        self.ids.insert(event_id.to_owned());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BookEventsHandler, RecentEvents};
+    use common::events::{
+        constants::Topics,
+        dto::{CreatedBook, CreatedBookBuilder},
+        envelope::EventEnvelope,
+    };
+    use common::isbn::Isbn;
+    use kafka::memory::InMemoryBroker;
+    use kafka::producer::Producer;
+
+    #[test]
+    fn test_recent_events_forgets_oldest() {
+        let mut recent_events = RecentEvents::new(2);
+        assert!(recent_events.insert("a"));
+        assert!(!recent_events.insert("a"));
+        assert!(recent_events.insert("b"));
+        assert!(recent_events.insert("c"));
+        assert!(recent_events.insert("a"));
+        assert!(!recent_events.insert("c"));
+    }
+
+    #[tokio::test]
+    async fn test_redelivered_events_are_handled_once() {
+        let broker = InMemoryBroker::new();
+        let topic = Topics::BookCreated.to_string();
+        let event = EventEnvelope::new(
+            "books_api",
+            CreatedBookBuilder::default()
+                .id(1)
+                .title("Dune".to_string())
+                .isbn(Isbn::parse("9780441172719").unwrap())
+                .build()
+                .unwrap(),
+        );
+        let producer = broker.producer(topic.clone());
+        for _ in 0..2 {
+            producer.produce(1, event.clone()).await.unwrap();
+        }
+
+        let handler = BookEventsHandler::new();
+        let consumed = broker
+            .consumer("books_analytics", topic)
+            .consume_available_keyed::<i32, EventEnvelope<CreatedBook>, _>(&handler)
+            .await
+            .unwrap();
+        assert_eq!(consumed, 2);
+        assert_eq!(handler.recent_events.lock().unwrap().ids.len(), 1);
+    }
+}
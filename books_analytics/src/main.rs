@@ -1,45 +1,89 @@
-use common::events::{constants::Topics, dto::CreatedBook};
-use kafka::consumer::KafkaConsumer;
-use tokio::sync::mpsc;
-use tracing::info;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+mod book_events_handler;
+mod metrics_server;
+
+use book_events_handler::BookEventsHandler;
+use common::events::{
+    constants::Topics,
+    dto::{CreatedBook, DeletedBook, UpdatedBook},
+    envelope::EventEnvelope,
+};
+use common::metrics::init_prometheus_exporter;
+use common::settings::Settings;
+use common::shutdown::shutdown_token;
+use kafka::consumer::{Consumer, KafkaConsumer, KafkaConsumerError};
+use metrics_server::start_metrics_server;
+use std::future::Future;
+use telemetry::TelemetryConfig;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    opentelemetry::global::set_text_map_propagator(opentelemetry_zipkin::Propagator::new());
-    let tracer = opentelemetry_zipkin::new_pipeline()
-        .with_service_name("books_analytics".to_owned())
-        .with_service_address("127.0.0.1:8080".parse().unwrap())
-        .with_collector_endpoint("http://localhost:9411/api/v2/spans")
-        .install_batch(opentelemetry::runtime::Tokio)
-        .expect("unable to install zipkin tracer");
-    let tracer = tracing_opentelemetry::layer().with_tracer(tracer.clone());
-
-    let subscriber = tracing_subscriber::fmt::layer().json();
+    let settings = Settings::load("books_analytics")?;
+    let _telemetry = telemetry::init(TelemetryConfig::from_settings(
+        &settings.telemetry,
+        settings.http.bind_address(),
+    ))?;
+    let metrics_exporter = init_prometheus_exporter()?;
+    let consumer = |topic: Topics| {
+        KafkaConsumer::new(
+            settings.kafka.bootstrap_servers.clone(),
+            settings.schema_registry.url.clone(),
+            settings.kafka.group_id.clone(),
+            topic.to_string(),
+        )
+        .with_max_concurrency(settings.kafka.max_concurrency)
+    };
+    let created_consumer = consumer(Topics::BookCreated);
+    let updated_consumer = consumer(Topics::BookUpdated);
+    let deleted_consumer = consumer(Topics::BookDeleted);
 
-    let level = EnvFilter::new("debug".to_owned());
-
-    tracing_subscriber::registry()
-        .with(subscriber)
-        .with(level)
-        .with(tracer)
-        .init();
-    let kakfa_consumer = KafkaConsumer::new(
-        "localhost:9092".to_string(),
-        "http://localhost:8081".to_string(),
-        "books-created-consumer".to_string(),
-        Topics::BookCreated.to_string(),
+    let shutdown = shutdown_token();
+    tokio::spawn(start_metrics_server(
+        metrics_exporter,
+        settings.http.bind_address(),
+        shutdown.clone(),
+    ));
+    info!("Starting book event consumers");
+    let handler = BookEventsHandler::new();
+    tokio::join!(
+        stop_on_error(
+            Topics::BookCreated,
+            created_consumer.consume_keyed_partitioned::<i32, EventEnvelope<CreatedBook>, _>(
+                &handler,
+                shutdown.clone(),
+            ),
+            &shutdown,
+        ),
+        stop_on_error(
+            Topics::BookUpdated,
+            updated_consumer.consume_keyed_partitioned::<i32, EventEnvelope<UpdatedBook>, _>(
+                &handler,
+                shutdown.clone(),
+            ),
+            &shutdown,
+        ),
+        stop_on_error(
+            Topics::BookDeleted,
+            deleted_consumer.consume_keyed_partitioned::<i32, EventEnvelope<DeletedBook>, _>(
+                &handler,
+                shutdown.clone(),
+            ),
+            &shutdown,
+        ),
     );
+    Ok(())
+}
 
-    let (sender, mut receiver) = mpsc::unbounded_channel::<CreatedBook>();
-    tokio::spawn(async move {
-        info!("Strarting book created consumer");
-        kakfa_consumer.consume(sender.clone()).await;
-    });
-
-    while let Some(message) = receiver.recv().await {
-        info!("Consumed messaged {:?}", message)
+/// Stops the other consumers when one of them fails, so the service exits
+/// instead of running with a topic silently unconsumed.
+async fn stop_on_error(
+    topic: Topics,
+    consume: impl Future<Output = Result<(), KafkaConsumerError>>,
+    shutdown: &CancellationToken,
+) {
+    if let Err(e) = consume.await {
+        error!("{} consumer stopped: {}", topic, e);
+        shutdown.cancel();
     }
-    opentelemetry::global::shutdown_tracer_provider();
-    Ok(())
 }
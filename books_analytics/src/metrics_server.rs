@@ -0,0 +1,20 @@
+use axum::{routing::get, Extension, Router};
+use common::metrics::{metrics_handler, PrometheusExporter};
+use std::net::SocketAddr;
+use tokio_util::sync::CancellationToken;
+
+/// Serves `/metrics` for scraping until `shutdown` is cancelled.
+pub async fn start_metrics_server(
+    exporter: PrometheusExporter,
+    addr: SocketAddr,
+    shutdown: CancellationToken,
+) {
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .layer(Extension(exporter));
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await
+        .unwrap()
+}
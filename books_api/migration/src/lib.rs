@@ -1,12 +1,22 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20230620_000001_create_outbox_table;
+mod m20230625_000001_add_book_search_vector;
+mod m20230705_000001_add_outbox_parked_at;
+mod m20230712_000001_add_outbox_locked_until;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_table::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20230620_000001_create_outbox_table::Migration),
+            Box::new(m20230625_000001_add_book_search_vector::Migration),
+            Box::new(m20230705_000001_add_outbox_parked_at::Migration),
+            Box::new(m20230712_000001_add_outbox_locked_until::Migration),
+        ]
     }
 }
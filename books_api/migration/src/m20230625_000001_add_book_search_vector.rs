@@ -0,0 +1,35 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Generated tsvector columns have no sea-query builder, so this one is raw SQL.
+        let connection = manager.get_connection();
+        connection
+            .execute_unprepared(
+                "ALTER TABLE book ADD COLUMN IF NOT EXISTS search_vector tsvector \
+                 GENERATED ALWAYS AS (to_tsvector('simple', title)) STORED",
+            )
+            .await?;
+        connection
+            .execute_unprepared(
+                "CREATE INDEX IF NOT EXISTS idx_book_search_vector ON book USING GIN (search_vector)",
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let connection = manager.get_connection();
+        connection
+            .execute_unprepared("DROP INDEX IF EXISTS idx_book_search_vector")
+            .await?;
+        connection
+            .execute_unprepared("ALTER TABLE book DROP COLUMN IF EXISTS search_vector")
+            .await?;
+        Ok(())
+    }
+}
@@ -1,9 +1,51 @@
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Builder, Debug)]
+#[derive(Serialize, Deserialize, Builder, Clone, Debug)]
 pub struct Book {
     pub id: i32,
     pub title: String,
     pub isbn: String,
 }
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ListBooksRequest {
+    pub cursor: Option<String>,
+    pub limit: Option<u64>,
+    pub title_prefix: Option<String>,
+    pub isbn: Option<String>,
+    #[serde(default)]
+    pub sort: SortOrder,
+    #[serde(default)]
+    pub include_total: bool,
+}
+
+#[derive(Serialize, Deserialize, Builder, Debug)]
+pub struct BookPage {
+    pub books: Vec<Book>,
+    pub next_cursor: Option<String>,
+    pub total: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchBooksRequest {
+    pub q: String,
+    pub limit: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Builder, Clone, Debug)]
+pub struct BookSearchHit {
+    pub id: i32,
+    pub title: String,
+    pub isbn: String,
+    pub rank: f32,
+    pub snippet: String,
+}
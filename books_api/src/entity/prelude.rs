@@ -1,3 +1,4 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.11.3
 
 pub use super::book::Entity as Book;
+pub use super::outbox::Entity as Outbox;
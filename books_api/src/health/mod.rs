@@ -0,0 +1,189 @@
+use crate::repository::Repository;
+use crate::service::book_events_producer::BookEventsProducer;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Up,
+    Down,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DependencyHealth {
+    pub status: HealthStatus,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DependencyChecks {
+    pub database: DependencyHealth,
+    pub kafka: DependencyHealth,
+    pub schema_registry: DependencyHealth,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LivenessReport {
+    pub status: HealthStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReadinessReport {
+    pub status: HealthStatus,
+    pub shutting_down: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checks: Option<DependencyChecks>,
+}
+
+/// Probes the dependencies books_api needs in order to serve traffic.
+#[derive(Clone)]
+pub struct HealthChecker {
+    repository: Repository,
+    book_events_producer: BookEventsProducer,
+    schema_registry_url: String,
+    shutdown: CancellationToken,
+    check_timeout: Duration,
+}
+
+impl HealthChecker {
+    pub fn new(
+        repository: Repository,
+        book_events_producer: BookEventsProducer,
+        schema_registry_url: String,
+        shutdown: CancellationToken,
+    ) -> Self {
+        Self {
+            repository,
+            book_events_producer,
+            schema_registry_url,
+            shutdown,
+            check_timeout: DEFAULT_CHECK_TIMEOUT,
+        }
+    }
+
+    /// The process is up and serving HTTP; dependencies are not consulted.
+    pub fn liveness(&self) -> LivenessReport {
+        LivenessReport {
+            status: HealthStatus::Up,
+        }
+    }
+
+    /// Not ready as soon as shutdown starts; the server keeps accepting
+    /// connections for a grace period so the orchestrator can stop routing
+    /// traffic here before the listener closes.
+    pub async fn readiness(&self) -> ReadinessReport {
+        if self.shutdown.is_cancelled() {
+            return ReadinessReport {
+                status: HealthStatus::Down,
+                shutting_down: true,
+                checks: None,
+            };
+        }
+        let (database, kafka, schema_registry) = tokio::join!(
+            self.check_database(),
+            self.check_kafka(),
+            self.check_schema_registry()
+        );
+        let checks = DependencyChecks {
+            database,
+            kafka,
+            schema_registry,
+        };
+        ReadinessReport {
+            status: overall_status(&checks),
+            shutting_down: false,
+            checks: Some(checks),
+        }
+    }
+
+    async fn check_database(&self) -> DependencyHealth {
+        timed(self.check_timeout, async {
+            self.repository.ping().await.map_err(|e| e.to_string())
+        })
+        .await
+    }
+
+    async fn check_kafka(&self) -> DependencyHealth {
+        let book_events_producer = self.book_events_producer.clone();
+        let check_timeout = self.check_timeout;
+        timed(check_timeout, async move {
+            // Metadata requests block inside librdkafka.
+            tokio::task::spawn_blocking(move || book_events_producer.fetch_metadata(check_timeout))
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|e| e.to_string())
+        })
+        .await
+    }
+
+    async fn check_schema_registry(&self) -> DependencyHealth {
+        timed(self.check_timeout, async {
+            kafka::util::check_schema_registry(&self.schema_registry_url, self.check_timeout)
+                .await
+                .map_err(|e| e.to_string())
+        })
+        .await
+    }
+}
+
+fn overall_status(checks: &DependencyChecks) -> HealthStatus {
+    if [&checks.database, &checks.kafka, &checks.schema_registry]
+        .iter()
+        .all(|check| check.status == HealthStatus::Up)
+    {
+        HealthStatus::Up
+    } else {
+        HealthStatus::Down
+    }
+}
+
+async fn timed(
+    timeout: Duration,
+    check: impl Future<Output = Result<(), String>>,
+) -> DependencyHealth {
+    let started = Instant::now();
+    let result = match tokio::time::timeout(timeout, check).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {:?}", timeout)),
+    };
+    let latency_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(()) => DependencyHealth {
+            status: HealthStatus::Up,
+            latency_ms,
+            error: None,
+        },
+        Err(error) => DependencyHealth {
+            status: HealthStatus::Down,
+            latency_ms,
+            error: Some(error),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{timed, HealthStatus};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_timed_check_status() {
+        let up = timed(Duration::from_secs(1), async { Ok(()) }).await;
+        assert_eq!(up.status, HealthStatus::Up);
+        assert_eq!(up.error, None);
+
+        let down = timed(Duration::from_secs(1), async { Err("refused".to_owned()) }).await;
+        assert_eq!(down.status, HealthStatus::Down);
+        assert_eq!(down.error, Some("refused".to_owned()));
+
+        let timed_out = timed(Duration::from_millis(10), std::future::pending()).await;
+        assert_eq!(timed_out.status, HealthStatus::Down);
+    }
+}
@@ -1,40 +1,284 @@
+use crate::dto::{Book, BookPage, BookSearchHit, ListBooksRequest, SearchBooksRequest};
+use crate::health::{HealthChecker, HealthStatus};
+use crate::repository::RepositoryError;
 use crate::service::{Service, ServiceError};
-use axum::{http::StatusCode, response::IntoResponse, routing::post, Extension, Json, Router};
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        MatchedPath, Path, Query, State,
+    },
+    http::{HeaderValue, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
 use axum_tracing_opentelemetry::opentelemetry_tracing_layer;
+use common::metrics::{metrics_handler, PrometheusExporter};
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::{global, Context, KeyValue};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use std::net::SocketAddr;
-use tracing::error;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use uuid::Uuid;
 
-pub async fn start_http_server(service: Service) {
-    let books_router = Router::new().route("/", post(create_book));
-    let api_router = Router::new().nest("/books", books_router);
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The id of the request being handled on this task, if any.
+pub(crate) fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Serves until `shutdown` is cancelled. Readiness reports down at once, but
+/// connections are still accepted for `readiness_grace_period`; after that the
+/// listener closes and in-flight requests get up to `drain_timeout` to complete.
+pub async fn start_http_server(
+    service: Service,
+    health_checker: HealthChecker,
+    metrics_exporter: PrometheusExporter,
+    addr: SocketAddr,
+    shutdown: CancellationToken,
+    readiness_grace_period: Duration,
+    drain_timeout: Duration,
+) {
+    let api_router = Router::new().nest("/books", books_router());
+    let health_router = Router::new()
+        .route("/live", get(liveness))
+        .route("/ready", get(readiness));
     let app = Router::new()
         .nest("/api", api_router)
+        .route_layer(middleware::from_fn_with_state(
+            HttpMetrics::new(),
+            track_metrics,
+        ))
+        .layer(middleware::from_fn(request_id))
         .layer(opentelemetry_tracing_layer())
-        .layer(Extension(service));
-    let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
-    axum::Server::bind(&addr)
+        .layer(Extension(service))
+        .nest("/health", health_router.layer(Extension(health_checker)))
+        .route(
+            "/metrics",
+            get(metrics_handler).layer(Extension(metrics_exporter)),
+        );
+    let graceful_shutdown = shutdown.clone();
+    let server = axum::Server::bind(&addr)
         .serve(app.into_make_service())
-        .await
-        .unwrap()
+        .with_graceful_shutdown(async move {
+            graceful_shutdown.cancelled().await;
+            info!(
+                "Reporting not ready; closing the listener in {:?}",
+                readiness_grace_period
+            );
+            tokio::time::sleep(readiness_grace_period).await;
+            info!("Draining in-flight requests");
+        });
+    let drain_deadline = async {
+        shutdown.cancelled().await;
+        tokio::time::sleep(readiness_grace_period + drain_timeout).await;
+    };
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = drain_deadline => warn!("In-flight requests did not finish within {:?}", drain_timeout),
+    }
+}
+
+fn books_router() -> Router {
+    Router::new()
+        .route("/", post(create_book).get(list_books))
+        .route("/search", get(search_books))
+        .route(
+            "/:id",
+            get(get_book)
+                .put(replace_book)
+                .patch(update_book)
+                .delete(delete_book),
+        )
+}
+
+/// Uses the caller's `x-request-id` when present so errors can be correlated across services.
+async fn request_id<B>(request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let mut response = REQUEST_ID
+        .scope(request_id.clone(), next.run(request))
+        .await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+#[derive(Clone)]
+struct HttpMetrics {
+    requests: Counter<u64>,
+    duration: Histogram<f64>,
+}
+
+impl HttpMetrics {
+    fn new() -> Self {
+        let meter = global::meter("books_api");
+        Self {
+            requests: meter
+                .u64_counter("http.server.requests")
+                .with_description("HTTP requests, by method, route and status")
+                .init(),
+            duration: meter
+                .f64_histogram("http.server.duration.seconds")
+                .with_description("HTTP request latency, by method, route and status")
+                .init(),
+        }
+    }
+}
+
+/// Labels by the matched route template rather than the raw path to keep
+/// cardinality bounded.
+async fn track_metrics<B>(
+    State(http_metrics): State<HttpMetrics>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|matched_path| matched_path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let method = request.method().to_string();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let attributes = [
+        KeyValue::new("method", method),
+        KeyValue::new("route", route),
+        KeyValue::new("status", i64::from(response.status().as_u16())),
+    ];
+    let context = Context::current();
+    http_metrics.requests.add(&context, 1, &attributes);
+    http_metrics
+        .duration
+        .record(&context, started.elapsed().as_secs_f64(), &attributes);
+    response
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    ValidationError,
+    BadRequest,
+    NotFound,
+    Conflict,
+    ServiceUnavailable,
+    InternalError,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ErrorResponse {
+    code: ErrorCode,
+    message: String,
+    field: Option<String>,
+    request_id: Option<String>,
 }
 
 impl IntoResponse for ServiceError {
     fn into_response(self) -> axum::response::Response {
         error!("Service Error {}", self);
-        let (status, error_message) = match self {
-            ServiceError::RepositoryError(re) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, re.to_string())
+        let (status, code, message, field) = match self {
+            ServiceError::BookNotFound(_) => (
+                StatusCode::NOT_FOUND,
+                ErrorCode::NotFound,
+                self.to_string(),
+                None,
+            ),
+            ServiceError::InvalidIsbn(_) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorCode::ValidationError,
+                self.to_string(),
+                Some("isbn".to_owned()),
+            ),
+            ServiceError::InvalidCursor => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorCode::ValidationError,
+                self.to_string(),
+                Some("cursor".to_owned()),
+            ),
+            ServiceError::EmptySearchQuery => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorCode::ValidationError,
+                self.to_string(),
+                Some("q".to_owned()),
+            ),
+            ServiceError::InvalidBody(ref rejection) => {
+                rejection_error(rejection.status(), self.to_string(), None)
+            }
+            ServiceError::InvalidPath(ref rejection) => {
+                rejection_error(rejection.status(), self.to_string(), Some("id".to_owned()))
             }
-            e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            ServiceError::InvalidQuery(ref rejection) => {
+                rejection_error(rejection.status(), self.to_string(), None)
+            }
+            ServiceError::RepositoryError(RepositoryError::NotFound) => (
+                StatusCode::NOT_FOUND,
+                ErrorCode::NotFound,
+                RepositoryError::NotFound.to_string(),
+                None,
+            ),
+            ServiceError::RepositoryError(RepositoryError::ConstraintViolation {
+                constraint,
+                field,
+            }) => (
+                StatusCode::CONFLICT,
+                ErrorCode::Conflict,
+                match &field {
+                    Some(field) => format!("A book with this {} already exists", field),
+                    None => format!("Constraint {} violated", constraint),
+                },
+                field,
+            ),
+            ServiceError::RepositoryError(re @ RepositoryError::ConnectionError(_)) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorCode::ServiceUnavailable,
+                re.to_string(),
+                None,
+            ),
+            _ => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                "Internal server error".to_owned(),
+                None,
+            ),
         };
 
-        let body = Json(json!({ "error": error_message }));
+        let body = Json(ErrorResponse {
+            code,
+            message,
+            field,
+            request_id: current_request_id(),
+        });
         (status, body).into_response()
     }
 }
 
+/// Requests the extractors reject keep the status axum chose for them.
+fn rejection_error(
+    status: StatusCode,
+    message: String,
+    field: Option<String>,
+) -> (StatusCode, ErrorCode, String, Option<String>) {
+    let code = if status == StatusCode::UNPROCESSABLE_ENTITY {
+        ErrorCode::ValidationError
+    } else {
+        ErrorCode::BadRequest
+    };
+    (status, code, message, field)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct CreateBookRequest {
     title: String,
@@ -42,31 +286,293 @@ struct CreateBookRequest {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct CreateBookResponse {
+struct ReplaceBookRequest {
+    title: String,
+    isbn: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct UpdateBookRequest {
+    title: Option<String>,
+    isbn: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BookResponse {
+    id: i32,
+    title: String,
+    isbn: String,
+}
+
+impl From<Book> for BookResponse {
+    fn from(book: Book) -> Self {
+        Self {
+            id: book.id,
+            title: book.title,
+            isbn: book.isbn,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BookSearchHitResponse {
     id: i32,
     title: String,
     isbn: String,
+    rank: f32,
+    snippet: String,
+}
+
+impl From<BookSearchHit> for BookSearchHitResponse {
+    fn from(search_hit: BookSearchHit) -> Self {
+        Self {
+            id: search_hit.id,
+            title: search_hit.title,
+            isbn: search_hit.isbn,
+            rank: search_hit.rank,
+            snippet: search_hit.snippet,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BookPageResponse {
+    books: Vec<BookResponse>,
+    next_cursor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<u64>,
+}
+
+impl From<BookPage> for BookPageResponse {
+    fn from(book_page: BookPage) -> Self {
+        Self {
+            books: book_page
+                .books
+                .into_iter()
+                .map(BookResponse::from)
+                .collect(),
+            next_cursor: book_page.next_cursor,
+            total: book_page.total,
+        }
+    }
+}
+
+async fn liveness(Extension(health_checker): Extension<HealthChecker>) -> impl IntoResponse {
+    Json(health_checker.liveness())
+}
+
+async fn readiness(Extension(health_checker): Extension<HealthChecker>) -> impl IntoResponse {
+    let report = health_checker.readiness().await;
+    let status = match report.status {
+        HealthStatus::Up => StatusCode::OK,
+        HealthStatus::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(report))
 }
 
 async fn create_book(
     Extension(service): Extension<Service>,
-    Json(create_book_request): Json<CreateBookRequest>,
-) -> impl IntoResponse {
-    let created_book_result = service
+    create_book_request: Result<Json<CreateBookRequest>, JsonRejection>,
+) -> Result<impl IntoResponse, ServiceError> {
+    let Json(create_book_request) = create_book_request?;
+    let created_book = service
         .create_and_publish_book(create_book_request.title, create_book_request.isbn)
-        .await;
-    return match created_book_result {
-        Ok(created_book) => (
-            StatusCode::CREATED,
-            Json(json!(CreateBookResponse {
-                id: created_book.id,
-                title: created_book.title,
-                isbn: created_book.isbn,
-            })),
-        ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "error": e.to_string() })),
-        ),
+        .await?;
+    Ok((StatusCode::CREATED, Json(BookResponse::from(created_book))))
+}
+
+async fn get_book(
+    Extension(service): Extension<Service>,
+    id: Result<Path<i32>, PathRejection>,
+) -> Result<impl IntoResponse, ServiceError> {
+    let Path(id) = id?;
+    let book = service.get_book(id).await?;
+    Ok(Json(BookResponse::from(book)))
+}
+
+async fn list_books(
+    Extension(service): Extension<Service>,
+    list_books_request: Result<Query<ListBooksRequest>, QueryRejection>,
+) -> Result<impl IntoResponse, ServiceError> {
+    let Query(list_books_request) = list_books_request?;
+    let book_page = service.list_books(list_books_request).await?;
+    Ok(Json(BookPageResponse::from(book_page)))
+}
+
+async fn search_books(
+    Extension(service): Extension<Service>,
+    search_books_request: Result<Query<SearchBooksRequest>, QueryRejection>,
+) -> Result<impl IntoResponse, ServiceError> {
+    let Query(search_books_request) = search_books_request?;
+    let search_hits = service.search_books(search_books_request).await?;
+    Ok(Json(
+        search_hits
+            .into_iter()
+            .map(BookSearchHitResponse::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+async fn replace_book(
+    Extension(service): Extension<Service>,
+    id: Result<Path<i32>, PathRejection>,
+    replace_book_request: Result<Json<ReplaceBookRequest>, JsonRejection>,
+) -> Result<impl IntoResponse, ServiceError> {
+    let Path(id) = id?;
+    let Json(replace_book_request) = replace_book_request?;
+    let book = service
+        .update_book(
+            id,
+            Some(replace_book_request.title),
+            Some(replace_book_request.isbn),
+        )
+        .await?;
+    Ok(Json(BookResponse::from(book)))
+}
+
+async fn update_book(
+    Extension(service): Extension<Service>,
+    id: Result<Path<i32>, PathRejection>,
+    update_book_request: Result<Json<UpdateBookRequest>, JsonRejection>,
+) -> Result<impl IntoResponse, ServiceError> {
+    let Path(id) = id?;
+    let Json(update_book_request) = update_book_request?;
+    let book = service
+        .update_book(id, update_book_request.title, update_book_request.isbn)
+        .await?;
+    Ok(Json(BookResponse::from(book)))
+}
+
+async fn delete_book(
+    Extension(service): Extension<Service>,
+    id: Result<Path<i32>, PathRejection>,
+) -> Result<impl IntoResponse, ServiceError> {
+    let Path(id) = id?;
+    service.delete_book(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{books_router, ErrorCode, ErrorResponse, REQUEST_ID};
+    use crate::repository::{Repository, RepositoryError};
+    use crate::service::{Service, ServiceError};
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+        response::IntoResponse,
+        Extension,
     };
+    use tower::ServiceExt;
+
+    async fn error_response(error: ServiceError) -> (StatusCode, ErrorResponse) {
+        let response = REQUEST_ID
+            .scope("request-1".to_string(), async { error.into_response() })
+            .await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    /// Sends `request` to the books routes. Requests that get past extraction
+    /// fail, as the repository has no database behind it.
+    async fn books_response(request: Request<Body>) -> (StatusCode, ErrorResponse) {
+        let response = books_router()
+            .layer(Extension(Service::new(Repository::disconnected())))
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_malformed_body_is_bad_request() {
+        let (status, body) = books_response(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from("{\"title\":"))
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.code, ErrorCode::BadRequest);
+
+        let (status, body) = books_response(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from("{\"title\":\"Dune\"}"))
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.code, ErrorCode::ValidationError);
+
+        let (status, body) = books_response(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .body(Body::from(
+                    "{\"title\":\"Dune\",\"isbn\":\"9780441172719\"}",
+                ))
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(body.code, ErrorCode::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn test_non_integer_id_is_bad_request() {
+        let (status, body) = books_response(
+            Request::builder()
+                .method(Method::DELETE)
+                .uri("/dune")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.code, ErrorCode::BadRequest);
+        assert_eq!(body.field, Some("id".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_constraint_violation_is_conflict() {
+        let (status, body) = error_response(ServiceError::RepositoryError(
+            RepositoryError::ConstraintViolation {
+                constraint: "book_title_key".to_string(),
+                field: Some("title".to_string()),
+            },
+        ))
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body.code, ErrorCode::Conflict);
+        assert_eq!(body.field, Some("title".to_string()));
+        assert_eq!(body.request_id, Some("request-1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_book_not_found_is_not_found() {
+        let (status, body) = error_response(ServiceError::BookNotFound(1)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.code, ErrorCode::NotFound);
+        assert_eq!(body.field, None);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_isbn_is_unprocessable() {
+        let (status, body) = error_response(ServiceError::InvalidIsbn(
+            common::isbn::Isbn::parse("ISBN").unwrap_err(),
+        ))
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.code, ErrorCode::ValidationError);
+        assert_eq!(body.field, Some("isbn".to_string()));
+    }
 }
@@ -0,0 +1,263 @@
+use async_trait::async_trait;
+use common::events::{
+    constants::Topics,
+    dto::{CreatedBook, DeletedBook, UpdatedBook},
+    envelope::EventEnvelope,
+    event_schemas,
+};
+use kafka::format::Serde;
+use kafka::key::MessageKey;
+use kafka::producer::{
+    DeliveryReport, KafkaProducer, KafkaProducerError, Producer, CORRELATION_ID_HEADER,
+    EVENT_ID_HEADER,
+};
+use kafka::util::{
+    check_schema_compatibility, register_schema, CompatibilityCheck, CompatibilityCheckError,
+};
+use schema_registry_converter::error::SRCError;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Produces enveloped events on any [`Producer`].
+#[async_trait]
+pub trait EventProducer: Producer {
+    /// Produces `event` and copies its id and correlation id into headers, so
+    /// they can be read without decoding the payload.
+    async fn produce_event<K: MessageKey, T: Send + 'static>(
+        &self,
+        key: K,
+        event: EventEnvelope<T>,
+    ) -> Result<DeliveryReport, KafkaProducerError>
+    where
+        Self::Serde: Serde<EventEnvelope<T>>,
+    {
+        let event_id = event.event_id.clone();
+        let correlation_id = event.correlation_id.clone();
+        let mut headers = vec![(EVENT_ID_HEADER, event_id.as_str())];
+        if let Some(correlation_id) = &correlation_id {
+            headers.push((CORRELATION_ID_HEADER, correlation_id.as_str()));
+        }
+        self.produce_with_headers(key, event, &headers).await
+    }
+}
+
+impl<P: Producer> EventProducer for P {}
+
+#[derive(Clone)]
+pub struct BookEventsProducer<P = KafkaProducer> {
+    created_producer: P,
+    updated_producer: P,
+    deleted_producer: P,
+}
+
+#[derive(Error, Debug)]
+pub enum BookEventsProducerError {
+    #[error("KafkaProducer error")]
+    KafkaProducerError(#[from] KafkaProducerError),
+}
+
+impl BookEventsProducerError {
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            BookEventsProducerError::KafkaProducerError(e) => e.is_retriable(),
+        }
+    }
+}
+
+impl BookEventsProducer {
+    pub fn new(bootstrap_servers: String, schema_registry_url: String) -> Self {
+        Self::from_producer(KafkaProducer::new(
+            bootstrap_servers,
+            schema_registry_url,
+            Topics::BookCreated.to_string(),
+        ))
+    }
+
+    pub fn fetch_metadata(&self, timeout: Duration) -> Result<(), BookEventsProducerError> {
+        Ok(self.created_producer.fetch_metadata(timeout)?)
+    }
+}
+
+impl<P: Producer> BookEventsProducer<P>
+where
+    P::Serde: Serde<EventEnvelope<CreatedBook>>
+        + Serde<EventEnvelope<UpdatedBook>>
+        + Serde<EventEnvelope<DeletedBook>>,
+{
+    /// Publishes each event type to its own topic through `producer`'s client,
+    /// keyed by book id.
+    pub fn from_producer(producer: P) -> Self {
+        Self {
+            created_producer: producer.for_topic(Topics::BookCreated.to_string()),
+            updated_producer: producer.for_topic(Topics::BookUpdated.to_string()),
+            deleted_producer: producer.for_topic(Topics::BookDeleted.to_string()),
+        }
+    }
+
+    pub async fn publish_created_book(
+        &self,
+        book_id: i32,
+        event: EventEnvelope<CreatedBook>,
+    ) -> Result<DeliveryReport, BookEventsProducerError> {
+        Ok(self.created_producer.produce_event(book_id, event).await?)
+    }
+
+    pub async fn publish_updated_book(
+        &self,
+        book_id: i32,
+        event: EventEnvelope<UpdatedBook>,
+    ) -> Result<DeliveryReport, BookEventsProducerError> {
+        Ok(self.updated_producer.produce_event(book_id, event).await?)
+    }
+
+    pub async fn publish_deleted_book(
+        &self,
+        book_id: i32,
+        event: EventEnvelope<DeletedBook>,
+    ) -> Result<DeliveryReport, BookEventsProducerError> {
+        Ok(self.deleted_producer.produce_event(book_id, event).await?)
+    }
+
+    /// The producers share one client, so flushing it covers every topic.
+    pub fn flush(&self, timeout: Duration) -> Result<(), BookEventsProducerError> {
+        Ok(self.created_producer.flush(timeout)?)
+    }
+}
+
+/// Subject each event schema is registered under, matching the topic name
+/// strategy the producers encode with.
+fn value_subject(topic: Topics) -> String {
+    format!("{}-value", topic)
+}
+
+/// Registers the envelope schema of every book event under its topic's subject.
+pub async fn register_event_schemas(schema_registry_url: &str) -> Result<(), SRCError> {
+    for (topic, schema) in event_schemas() {
+        register_schema(schema_registry_url.to_owned(), value_subject(topic), schema).await?;
+    }
+    Ok(())
+}
+
+/// Checks every book event schema against the latest version of its subject.
+pub async fn check_event_schemas(
+    schema_registry_url: &str,
+) -> Result<Vec<(String, CompatibilityCheck)>, CompatibilityCheckError> {
+    let mut checks = Vec::new();
+    for (topic, schema) in event_schemas() {
+        let subject = value_subject(topic);
+        let check = check_schema_compatibility(schema_registry_url, &subject, &schema).await?;
+        checks.push((subject, check));
+    }
+    Ok(checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_event_schemas, register_event_schemas, BookEventsProducer};
+    use common::events::{
+        constants::Topics,
+        dto::{CreatedBook, CreatedBookBuilder, DeletedBook, DeletedBookBuilder},
+        envelope::EventEnvelope,
+    };
+    use common::isbn::Isbn;
+    use kafka::key::KeyFormat;
+    use kafka::memory::InMemoryBroker;
+    use kafka::mock_schema_registry::{CompatibilityLevel, MockSchemaRegistry};
+    use kafka::producer::EVENT_ID_HEADER;
+    use kafka::util::CompatibilityCheck;
+
+    #[tokio::test]
+    async fn test_publishes_to_event_topics() {
+        let broker = InMemoryBroker::new();
+        let book_events_producer = BookEventsProducer::from_producer(broker.producer("unused"));
+        let isbn = Isbn::parse("9780441172719").unwrap();
+        let created = EventEnvelope::new(
+            "books_api",
+            CreatedBookBuilder::default()
+                .id(1)
+                .title("Dune".to_string())
+                .isbn(isbn.clone())
+                .build()
+                .unwrap(),
+        );
+        let deleted = EventEnvelope::new(
+            "books_api",
+            DeletedBookBuilder::default()
+                .id(1)
+                .title("Dune".to_string())
+                .isbn(isbn.to_string())
+                .build()
+                .unwrap(),
+        );
+        book_events_producer
+            .publish_created_book(1, created.clone())
+            .await
+            .unwrap();
+        book_events_producer
+            .publish_deleted_book(1, deleted.clone())
+            .await
+            .unwrap();
+
+        let created_records = broker.records(&Topics::BookCreated.to_string());
+        assert_eq!(created_records.len(), 1);
+        assert_eq!(created_records[0].key.as_deref(), Some("1".as_bytes()));
+        assert_eq!(
+            created_records[0]
+                .decode_key::<i32>(KeyFormat::String)
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            created_records[0].header(EVENT_ID_HEADER),
+            Some(created.event_id.as_str())
+        );
+        let decoded: EventEnvelope<CreatedBook> = created_records[0].decode().unwrap();
+        assert_eq!(decoded.event_id, created.event_id);
+
+        let deleted_records = broker.records(&Topics::BookDeleted.to_string());
+        assert_eq!(deleted_records.len(), 1);
+        let decoded: EventEnvelope<DeletedBook> = deleted_records[0].decode().unwrap();
+        assert_eq!(decoded.event_id, deleted.event_id);
+        assert!(broker.records(&Topics::BookUpdated.to_string()).is_empty());
+        assert!(broker.records("unused").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_register_event_schemas() {
+        let registry = MockSchemaRegistry::start().await;
+        register_event_schemas(registry.url()).await.unwrap();
+        // Restarts re-register the same schemas without adding versions.
+        register_event_schemas(registry.url()).await.unwrap();
+
+        let expected: Vec<String> = [
+            Topics::BookCreated,
+            Topics::BookDeleted,
+            Topics::BookUpdated,
+        ]
+        .iter()
+        .map(|topic| format!("{}-value", topic))
+        .collect();
+        let mut subjects = registry.subjects();
+        subjects.sort();
+        assert_eq!(subjects, expected);
+        for subject in &expected {
+            assert_eq!(registry.schemas(subject).len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_event_schemas() {
+        let registry = MockSchemaRegistry::start_with(CompatibilityLevel::Full).await;
+        let checks = check_event_schemas(registry.url()).await.unwrap();
+        assert!(checks
+            .iter()
+            .all(|(_, check)| *check == CompatibilityCheck::NewSubject));
+
+        register_event_schemas(registry.url()).await.unwrap();
+        let checks = check_event_schemas(registry.url()).await.unwrap();
+        assert_eq!(checks.len(), 3);
+        assert!(checks
+            .iter()
+            .all(|(_, check)| *check == CompatibilityCheck::Compatible));
+    }
+}
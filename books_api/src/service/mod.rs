@@ -1,15 +1,36 @@
-pub mod book_created_producer;
-use self::book_created_producer::{BookCreatedProducer, BookCreatedProducerError};
-use crate::dto::{Book, BookBuilder, BookBuilderError};
-use crate::repository::{Repository, RepositoryError};
-use std::sync::Arc;
+pub mod book_events_producer;
+pub mod outbox_relay;
+use crate::dto::{
+    Book, BookBuilder, BookBuilderError, BookPage, BookPageBuilder, BookPageBuilderError,
+    BookSearchHit, BookSearchHitBuilder, BookSearchHitBuilderError, ListBooksRequest,
+    SearchBooksRequest, SortOrder,
+};
+use crate::entity::book::Model as BookModel;
+use crate::http_server::current_request_id;
+use crate::repository::{
+    BookListQuery, OutboxMessage, Repository, RepositoryError, SortOrder as RepositorySortOrder,
+};
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use common::events::{
+    constants::Topics,
+    dto::{
+        CreatedBookBuilder, CreatedBookBuilderError, DeletedBookBuilder, DeletedBookBuilderError,
+        FieldChange, UpdatedBookBuilder, UpdatedBookBuilderError,
+    },
+    envelope::EventEnvelope,
+};
+use common::isbn::{Isbn, IsbnError};
 use thiserror::Error;
 use tracing::{info_span, Instrument};
 
+const DEFAULT_PAGE_SIZE: u64 = 20;
+const MAX_PAGE_SIZE: u64 = 100;
+pub(crate) const EVENT_PRODUCER: &str = env!("CARGO_PKG_NAME");
+
 #[derive(Clone)]
 pub struct Service {
     repository: Repository,
-    book_created_producer: Arc<BookCreatedProducer>,
 }
 
 #[derive(Error, Debug)]
@@ -20,16 +41,49 @@ pub enum ServiceError {
     #[error("BookBuilder error")]
     BookBuilderError(#[from] BookBuilderError),
 
-    #[error("BookCreatedProducer error")]
-    BookCreatedProducer(#[from] BookCreatedProducerError),
+    #[error("BookPageBuilder error")]
+    BookPageBuilderError(#[from] BookPageBuilderError),
+
+    #[error("BookSearchHitBuilder error")]
+    BookSearchHitBuilderError(#[from] BookSearchHitBuilderError),
+
+    #[error("CreatedBookBuilder error")]
+    CreatedBookBuilderError(#[from] CreatedBookBuilderError),
+
+    #[error("UpdatedBookBuilder error")]
+    UpdatedBookBuilderError(#[from] UpdatedBookBuilderError),
+
+    #[error("DeletedBookBuilder error")]
+    DeletedBookBuilderError(#[from] DeletedBookBuilderError),
+
+    #[error("Outbox payload error")]
+    OutboxPayloadError(#[from] serde_json::Error),
+
+    #[error("Book {0} not found")]
+    BookNotFound(i32),
+
+    #[error("Invalid ISBN: {0}")]
+    InvalidIsbn(#[from] IsbnError),
+
+    #[error("Invalid cursor")]
+    InvalidCursor,
+
+    #[error("Search query must contain at least one word")]
+    EmptySearchQuery,
+
+    #[error("{}", .0.body_text())]
+    InvalidBody(#[from] JsonRejection),
+
+    #[error("{}", .0.body_text())]
+    InvalidPath(#[from] PathRejection),
+
+    #[error("{}", .0.body_text())]
+    InvalidQuery(#[from] QueryRejection),
 }
 
 impl Service {
-    pub fn new(repository: Repository, book_created_producer: BookCreatedProducer) -> Self {
-        Self {
-            repository,
-            book_created_producer: Arc::new(book_created_producer),
-        }
+    pub fn new(repository: Repository) -> Self {
+        Self { repository }
     }
 
     pub async fn create_and_publish_book(
@@ -37,34 +91,372 @@ impl Service {
         title: String,
         isbn: String,
     ) -> Result<Book, ServiceError> {
+        let isbn = Isbn::parse(&isbn)?;
+        let correlation_id = current_request_id();
         let span = info_span!("create_and_publish_book repository create_book");
-        let created_book_model = async move {
-            let created_book_model = self
-                .repository
-                .create_book(title, isbn)
-                .await
-                .map_err(|e| ServiceError::RepositoryError(e));
-            return created_book_model;
-        }
-        .instrument(span)
-        .await?;
-        let producer = self.book_created_producer.clone();
-        let created_book_id = created_book_model.clone().id;
-        let created_book_title = created_book_model.clone().title;
-        let created_book_isbn = created_book_model.clone().isbn;
-        let _ = tokio::task::spawn(async move {
-            let _ = producer
-                .publish_created_book(created_book_id, created_book_title, created_book_isbn)
-                .await;
-        })
-        .instrument(info_span!("publish_created_book"));
-
-        let book = BookBuilder::default()
-            .id(created_book_model.id)
-            .title(created_book_model.title)
-            .isbn(created_book_model.isbn)
+        let created_book_model = self
+            .repository
+            .create_book(title, isbn.to_string(), |book| {
+                let created_book = CreatedBookBuilder::default()
+                    .id(book.id)
+                    .title(book.title.clone())
+                    .isbn(isbn)
+                    .build()?;
+                let event = EventEnvelope::new(EVENT_PRODUCER, created_book)
+                    .with_correlation_id(correlation_id);
+                Ok::<_, ServiceError>(OutboxMessage {
+                    topic: Topics::BookCreated.to_string(),
+                    key: book.id.to_string(),
+                    payload: serde_json::to_value(event)?,
+                })
+            })
+            .instrument(span)
+            .await?;
+        build_book(created_book_model)
+    }
+
+    pub async fn get_book(&self, id: i32) -> Result<Book, ServiceError> {
+        let book_model = self
+            .repository
+            .get_book(id)
+            .instrument(info_span!("get_book repository get_book"))
+            .await?
+            .ok_or(ServiceError::BookNotFound(id))?;
+        build_book(book_model)
+    }
+
+    pub async fn list_books(
+        &self,
+        list_books_request: ListBooksRequest,
+    ) -> Result<BookPage, ServiceError> {
+        let title_prefix = list_books_request.title_prefix;
+        let isbn = list_books_request
+            .isbn
+            .as_deref()
+            .map(Isbn::parse)
+            .transpose()?
+            .map(String::from);
+        let sort = list_books_request.sort;
+        let fingerprint = filter_fingerprint(title_prefix.as_deref(), isbn.as_deref());
+        let query = BookListQuery {
+            after_id: list_books_request
+                .cursor
+                .as_deref()
+                .map(|cursor| decode_cursor(cursor, sort, fingerprint))
+                .transpose()?,
+            limit: list_books_request
+                .limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE),
+            title_prefix,
+            isbn,
+            sort: match sort {
+                SortOrder::Asc => RepositorySortOrder::Asc,
+                SortOrder::Desc => RepositorySortOrder::Desc,
+            },
+            include_total: list_books_request.include_total,
+        };
+        let book_page = self
+            .repository
+            .list_books(query)
+            .instrument(info_span!("list_books repository list_books"))
+            .await?;
+        let next_cursor = if book_page.has_more {
+            book_page
+                .books
+                .last()
+                .map(|book| encode_cursor(book.id, sort, fingerprint))
+        } else {
+            None
+        };
+        BookPageBuilder::default()
+            .books(
+                book_page
+                    .books
+                    .into_iter()
+                    .map(build_book)
+                    .collect::<Result<Vec<_>, _>>()?,
+            )
+            .next_cursor(next_cursor)
+            .total(book_page.total)
             .build()
-            .map_err(|e| ServiceError::BookBuilderError(e))?;
-        Ok(book)
+            .map_err(ServiceError::BookPageBuilderError)
+    }
+
+    pub async fn search_books(
+        &self,
+        search_books_request: SearchBooksRequest,
+    ) -> Result<Vec<BookSearchHit>, ServiceError> {
+        if !search_books_request.q.chars().any(char::is_alphanumeric) {
+            return Err(ServiceError::EmptySearchQuery);
+        }
+        let limit = search_books_request
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        self.repository
+            .search_books(&search_books_request.q, limit)
+            .instrument(info_span!("search_books repository search_books"))
+            .await?
+            .into_iter()
+            .map(|result| {
+                BookSearchHitBuilder::default()
+                    .id(result.id)
+                    .title(result.title)
+                    .isbn(result.isbn)
+                    .rank(result.rank)
+                    .snippet(result.snippet)
+                    .build()
+                    .map_err(ServiceError::BookSearchHitBuilderError)
+            })
+            .collect()
+    }
+
+    pub async fn update_book(
+        &self,
+        id: i32,
+        title: Option<String>,
+        isbn: Option<String>,
+    ) -> Result<Book, ServiceError> {
+        let isbn = isbn.as_deref().map(Isbn::parse).transpose()?;
+        let correlation_id = current_request_id();
+        let book_model = self
+            .repository
+            .update_book(id, title, isbn.map(String::from), |before, after| {
+                updated_book_message(before, after, correlation_id)
+            })
+            .instrument(info_span!("update_book repository update_book"))
+            .await?
+            .ok_or(ServiceError::BookNotFound(id))?;
+        build_book(book_model)
+    }
+
+    pub async fn delete_book(&self, id: i32) -> Result<(), ServiceError> {
+        let correlation_id = current_request_id();
+        let deleted = self
+            .repository
+            .delete_book(id, |book| deleted_book_message(book, correlation_id))
+            .instrument(info_span!("delete_book repository delete_book"))
+            .await?;
+        if !deleted {
+            return Err(ServiceError::BookNotFound(id));
+        }
+        Ok(())
+    }
+}
+
+/// Announces an update, or nothing when the update was a no-op.
+fn updated_book_message(
+    before: &BookModel,
+    after: &BookModel,
+    correlation_id: Option<String>,
+) -> Result<Option<OutboxMessage>, ServiceError> {
+    let changes = book_changes(before, after);
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    let updated_book = UpdatedBookBuilder::default()
+        .id(after.id)
+        .title(after.title.clone())
+        .isbn(after.isbn.clone())
+        .changes(changes)
+        .build()?;
+    let event =
+        EventEnvelope::new(EVENT_PRODUCER, updated_book).with_correlation_id(correlation_id);
+    Ok(Some(OutboxMessage {
+        topic: Topics::BookUpdated.to_string(),
+        key: after.id.to_string(),
+        payload: serde_json::to_value(event)?,
+    }))
+}
+
+fn deleted_book_message(
+    book: &BookModel,
+    correlation_id: Option<String>,
+) -> Result<OutboxMessage, ServiceError> {
+    let deleted_book = DeletedBookBuilder::default()
+        .id(book.id)
+        .title(book.title.clone())
+        .isbn(book.isbn.clone())
+        .build()?;
+    let event =
+        EventEnvelope::new(EVENT_PRODUCER, deleted_book).with_correlation_id(correlation_id);
+    Ok(OutboxMessage {
+        topic: Topics::BookDeleted.to_string(),
+        key: book.id.to_string(),
+        payload: serde_json::to_value(event)?,
+    })
+}
+
+/// The fields that differ between two versions of a book.
+fn book_changes(before: &BookModel, after: &BookModel) -> Vec<FieldChange> {
+    [
+        ("title", &before.title, &after.title),
+        ("isbn", &before.isbn, &after.isbn),
+    ]
+    .into_iter()
+    .filter(|(_, old_value, new_value)| old_value != new_value)
+    .map(|(field, old_value, new_value)| FieldChange {
+        field: field.to_owned(),
+        old_value: old_value.clone(),
+        new_value: new_value.clone(),
+    })
+    .collect()
+}
+
+/// A cursor is only valid for the sort order and filters it was issued for,
+/// so both are encoded next to the id of the last book on the page.
+fn encode_cursor(id: i32, sort: SortOrder, fingerprint: u64) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}.{:016x}.{}", sort_code(sort), fingerprint, id))
+}
+
+fn decode_cursor(cursor: &str, sort: SortOrder, fingerprint: u64) -> Result<i32, ServiceError> {
+    let cursor = URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or(ServiceError::InvalidCursor)?;
+    let mut parts = cursor.splitn(3, '.');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(cursor_sort), Some(cursor_fingerprint), Some(id))
+            if cursor_sort == sort_code(sort)
+                && cursor_fingerprint == format!("{:016x}", fingerprint) =>
+        {
+            id.parse().map_err(|_| ServiceError::InvalidCursor)
+        }
+        _ => Err(ServiceError::InvalidCursor),
+    }
+}
+
+fn sort_code(sort: SortOrder) -> &'static str {
+    match sort {
+        SortOrder::Asc => "a",
+        SortOrder::Desc => "d",
+    }
+}
+
+/// FNV-1a over the listing filters; stable across builds, unlike `DefaultHasher`.
+fn filter_fingerprint(title_prefix: Option<&str>, isbn: Option<&str>) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut write = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    };
+    for filter in [title_prefix, isbn] {
+        // Length-prefix each filter so `None`, `Some("")` and shifted values differ.
+        match filter {
+            Some(value) => {
+                write(&[1]);
+                write(&(value.len() as u64).to_le_bytes());
+                write(value.as_bytes());
+            }
+            None => write(&[0]),
+        }
+    }
+    hash
+}
+
+fn build_book(book_model: BookModel) -> Result<Book, ServiceError> {
+    BookBuilder::default()
+        .id(book_model.id)
+        .title(book_model.title)
+        .isbn(book_model.isbn)
+        .build()
+        .map_err(ServiceError::BookBuilderError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        book_changes, decode_cursor, deleted_book_message, encode_cursor, filter_fingerprint,
+        updated_book_message, ServiceError,
+    };
+    use crate::dto::SortOrder;
+    use crate::entity::book::Model as BookModel;
+    use common::events::dto::FieldChange;
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let fingerprint = filter_fingerprint(Some("Dune"), None);
+        let cursor = encode_cursor(42, SortOrder::Desc, fingerprint);
+        assert_eq!(
+            decode_cursor(&cursor, SortOrder::Desc, fingerprint).unwrap(),
+            42
+        );
+        assert!(matches!(
+            decode_cursor("not a cursor", SortOrder::Asc, fingerprint),
+            Err(ServiceError::InvalidCursor)
+        ));
+    }
+
+    #[test]
+    fn test_cursor_rejects_changed_query() {
+        let fingerprint = filter_fingerprint(Some("Dune"), None);
+        let cursor = encode_cursor(42, SortOrder::Asc, fingerprint);
+        assert!(matches!(
+            decode_cursor(&cursor, SortOrder::Desc, fingerprint),
+            Err(ServiceError::InvalidCursor)
+        ));
+        assert!(matches!(
+            decode_cursor(
+                &cursor,
+                SortOrder::Asc,
+                filter_fingerprint(Some("Emma"), None)
+            ),
+            Err(ServiceError::InvalidCursor)
+        ));
+        assert_ne!(
+            filter_fingerprint(None, None),
+            filter_fingerprint(Some(""), None)
+        );
+    }
+
+    #[test]
+    fn test_book_changes() {
+        let before = BookModel {
+            id: 1,
+            title: "Dune".to_string(),
+            isbn: "9780441172719".to_string(),
+        };
+        assert!(book_changes(&before, &before).is_empty());
+
+        let after = BookModel {
+            title: "Dune Messiah".to_string(),
+            ..before.clone()
+        };
+        assert_eq!(
+            book_changes(&before, &after),
+            vec![FieldChange {
+                field: "title".to_string(),
+                old_value: "Dune".to_string(),
+                new_value: "Dune Messiah".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_legacy_isbn_is_passed_on_as_stored() {
+        // Rows from before ISBN validation may hold ISBNs that do not parse.
+        let before = BookModel {
+            id: 1,
+            title: "Dune".to_string(),
+            isbn: "ISBN".to_string(),
+        };
+        let after = BookModel {
+            title: "Dune Messiah".to_string(),
+            ..before.clone()
+        };
+        let updated = updated_book_message(&before, &after, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.payload["payload"]["isbn"], "ISBN");
+        assert!(updated_book_message(&before, &before, None)
+            .unwrap()
+            .is_none());
+
+        let deleted = deleted_book_message(&before, None).unwrap();
+        assert_eq!(deleted.payload["payload"]["isbn"], "ISBN");
     }
 }
@@ -0,0 +1,293 @@
+use super::book_events_producer::{BookEventsProducer, BookEventsProducerError};
+use super::EVENT_PRODUCER;
+use crate::entity::outbox::Model as OutboxModel;
+use crate::repository::{Repository, RepositoryError};
+use common::events::{constants::Topics, dto::CreatedBook, envelope::EventEnvelope};
+use std::num::ParseIntError;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, warn, Instrument};
+
+/// Drains the outbox table onto Kafka, giving at-least-once delivery for
+/// events written alongside their database changes.
+pub struct OutboxRelay {
+    repository: Repository,
+    book_events_producer: Arc<BookEventsProducer>,
+    poll_interval: Duration,
+    batch_size: u64,
+    max_retries: u32,
+    retry_backoff: Duration,
+    /// Failed polls after which a message is parked instead of retried.
+    max_attempts: i32,
+    /// How long claimed messages are withheld from other relays; a relay that
+    /// dies mid-batch has its messages picked up again once this expires.
+    claim_lease: Duration,
+    cleanup_interval: Duration,
+    /// How long sent messages are kept before being deleted.
+    sent_retention: Duration,
+    flush_timeout: Duration,
+}
+
+#[derive(Error, Debug)]
+pub enum OutboxRelayError {
+    #[error("Repository error")]
+    RepositoryError(#[from] RepositoryError),
+
+    #[error("Outbox payload error")]
+    PayloadError(#[from] serde_json::Error),
+
+    #[error("Unknown outbox topic {0}")]
+    UnknownTopic(String),
+
+    #[error("Outbox key is not a book id")]
+    KeyError(#[from] ParseIntError),
+
+    #[error("BookEventsProducer error")]
+    BookEventsProducer(#[from] BookEventsProducerError),
+}
+
+impl OutboxRelayError {
+    /// Whether relaying the same message again may succeed.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            OutboxRelayError::RepositoryError(_) => true,
+            OutboxRelayError::BookEventsProducer(e) => e.is_retriable(),
+            OutboxRelayError::PayloadError(_)
+            | OutboxRelayError::UnknownTopic(_)
+            | OutboxRelayError::KeyError(_) => false,
+        }
+    }
+}
+
+impl OutboxRelay {
+    pub fn new(repository: Repository, book_events_producer: BookEventsProducer) -> Self {
+        Self {
+            repository,
+            book_events_producer: Arc::new(book_events_producer),
+            poll_interval: Duration::from_secs(1),
+            batch_size: 100,
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(200),
+            max_attempts: 10,
+            claim_lease: Duration::from_secs(5 * 60),
+            cleanup_interval: Duration::from_secs(60 * 60),
+            sent_retention: Duration::from_secs(7 * 24 * 60 * 60),
+            flush_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Relays until `shutdown` is cancelled. A batch already in progress is
+    /// finished and the producer queue flushed before returning.
+    pub async fn run(self, shutdown: CancellationToken) {
+        info!("Starting outbox relay");
+        let mut interval = tokio::time::interval(self.poll_interval);
+        let mut cleanup_interval = tokio::time::interval(self.cleanup_interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = cleanup_interval.tick() => {
+                    self.delete_sent_messages().await;
+                    continue;
+                }
+                _ = interval.tick() => {}
+            }
+            match self
+                .relay_pending_messages()
+                .instrument(info_span!("relay_pending_messages"))
+                .await
+            {
+                Ok(0) => {}
+                Ok(relayed) => info!("Relayed {} outbox messages", relayed),
+                Err(e) => error!("Error relaying outbox messages: {}", e),
+            }
+        }
+        info!("Stopping outbox relay, flushing producer");
+        let book_events_producer = self.book_events_producer.clone();
+        let flush_timeout = self.flush_timeout;
+        match tokio::task::spawn_blocking(move || book_events_producer.flush(flush_timeout)).await {
+            Ok(Ok(())) => info!("Producer flushed"),
+            Ok(Err(e)) => error!("Error flushing producer: {}", e),
+            Err(e) => error!("Producer flush task failed: {}", e),
+        }
+    }
+
+    /// Relays a batch of claimed messages. The batch holds at most one
+    /// message per key, so a failed message only holds back its own key; it
+    /// is parked once retrying cannot help or `max_attempts` is reached.
+    /// Each outcome is recorded on its own, so a failed update only leaves
+    /// that message to be relayed again once its lease expires.
+    async fn relay_pending_messages(&self) -> Result<usize, OutboxRelayError> {
+        let messages = self
+            .repository
+            .claim_pending_outbox_messages(self.batch_size, self.claim_lease)
+            .await?;
+        let mut relayed = 0;
+        for outbox_message in &messages {
+            let recorded = match self.publish_with_retries(outbox_message).await {
+                Ok(()) => {
+                    relayed += 1;
+                    self.repository
+                        .mark_outbox_message_sent(outbox_message.id)
+                        .await
+                }
+                Err(e) => {
+                    let park =
+                        !e.is_retriable() || outbox_message.attempts + 1 >= self.max_attempts;
+                    if park {
+                        error!(
+                            "Outbox message {} parked after {} attempts: {}",
+                            outbox_message.id,
+                            outbox_message.attempts + 1,
+                            e
+                        );
+                    } else {
+                        warn!(
+                            "Outbox message {} not relayed, will retry: {}",
+                            outbox_message.id, e
+                        );
+                    }
+                    self.repository
+                        .record_outbox_failure(outbox_message.id, e.to_string(), park)
+                        .await
+                }
+            };
+            if let Err(e) = recorded {
+                error!(
+                    "Error recording the outcome of outbox message {}: {}",
+                    outbox_message.id, e
+                );
+            }
+        }
+        Ok(relayed)
+    }
+
+    async fn delete_sent_messages(&self) {
+        match self
+            .repository
+            .delete_sent_outbox_messages(self.sent_retention)
+            .await
+        {
+            Ok(0) => {}
+            Ok(deleted) => info!("Deleted {} sent outbox messages", deleted),
+            Err(e) => error!("Error deleting sent outbox messages: {}", e),
+        }
+    }
+
+    async fn publish_with_retries(
+        &self,
+        outbox_message: &OutboxModel,
+    ) -> Result<(), OutboxRelayError> {
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            match self.publish(outbox_message).await {
+                Err(OutboxRelayError::BookEventsProducer(e))
+                    if e.is_retriable() && attempt < self.max_retries =>
+                {
+                    attempt += 1;
+                    warn!(
+                        "Publishing outbox message {} failed (attempt {}): {}",
+                        outbox_message.id, attempt, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn publish(&self, outbox_message: &OutboxModel) -> Result<(), OutboxRelayError> {
+        let key = outbox_message.key.parse::<i32>()?;
+        let payload = outbox_message.payload.clone();
+        let delivery_report = match outbox_message.topic.parse::<Topics>() {
+            Ok(Topics::BookCreated) => {
+                self.book_events_producer
+                    .publish_created_book(key, created_book_event(outbox_message)?)
+                    .await?
+            }
+            Ok(Topics::BookUpdated) => {
+                self.book_events_producer
+                    .publish_updated_book(key, serde_json::from_value(payload)?)
+                    .await?
+            }
+            Ok(Topics::BookDeleted) => {
+                self.book_events_producer
+                    .publish_deleted_book(key, serde_json::from_value(payload)?)
+                    .await?
+            }
+            Err(_) => return Err(OutboxRelayError::UnknownTopic(outbox_message.topic.clone())),
+        };
+        info!(
+            "Outbox message {} delivered to partition {} at offset {}",
+            outbox_message.id, delivery_report.partition, delivery_report.offset
+        );
+        Ok(())
+    }
+}
+
+/// Rows written before events were enveloped hold a bare `CreatedBook`; those
+/// are wrapped on the way out, timestamped with when the row was written.
+fn created_book_event(
+    outbox_message: &OutboxModel,
+) -> Result<EventEnvelope<CreatedBook>, serde_json::Error> {
+    serde_json::from_value(outbox_message.payload.clone()).or_else(|_| {
+        let created_book: CreatedBook = serde_json::from_value(outbox_message.payload.clone())?;
+        let mut event = EventEnvelope::new(EVENT_PRODUCER, created_book);
+        event.occurred_at = outbox_message.created_at.timestamp_millis();
+        Ok(event)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{created_book_event, CreatedBook};
+    use crate::entity::outbox::Model as OutboxModel;
+    use common::events::{
+        dto::CreatedBookBuilder, envelope::EventEnvelope, envelope::EventPayload,
+    };
+    use common::isbn::Isbn;
+    use sea_orm::prelude::DateTimeWithTimeZone;
+
+    fn outbox_message(payload: serde_json::Value) -> OutboxModel {
+        OutboxModel {
+            id: 1,
+            topic: "BookCreated".to_string(),
+            key: "1".to_string(),
+            payload,
+            created_at: DateTimeWithTimeZone::parse_from_rfc3339("2023-06-01T12:00:00Z").unwrap(),
+            sent_at: None,
+            attempts: 0,
+            last_error: None,
+            parked_at: None,
+            locked_until: None,
+        }
+    }
+
+    #[test]
+    fn test_created_book_event_payloads() {
+        let created_book = CreatedBookBuilder::default()
+            .id(1)
+            .title("Dune".to_string())
+            .isbn(Isbn::parse("9780441172719").unwrap())
+            .build()
+            .unwrap();
+
+        let event = EventEnvelope::new("books_api", created_book.clone())
+            .with_correlation_id(Some("request-1".to_string()));
+        let relayed =
+            created_book_event(&outbox_message(serde_json::to_value(&event).unwrap())).unwrap();
+        assert_eq!(relayed.event_id, event.event_id);
+        assert_eq!(relayed.correlation_id, event.correlation_id);
+
+        let legacy = created_book_event(&outbox_message(
+            serde_json::to_value(&created_book).unwrap(),
+        ))
+        .unwrap();
+        assert_eq!(legacy.occurred_at, 1_685_620_800_000);
+        assert_eq!(legacy.schema_version, CreatedBook::SCHEMA_VERSION);
+        assert_eq!(legacy.correlation_id, None);
+    }
+}
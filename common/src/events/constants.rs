@@ -1,6 +1,8 @@
-use strum::Display;
+use strum::{Display, EnumString};
 
-#[derive(Display)]
+#[derive(Display, EnumString, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Topics {
     BookCreated,
+    BookUpdated,
+    BookDeleted,
 }
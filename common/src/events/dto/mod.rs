@@ -1,10 +1,82 @@
+use super::envelope::EventPayload;
+use crate::isbn::Isbn;
 use apache_avro::AvroSchema;
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Builder, Clone, Debug, AvroSchema)]
 pub struct CreatedBook {
+    id: i32,
+    title: String,
+    isbn: Isbn,
+}
+
+impl EventPayload for CreatedBook {
+    const SCHEMA_VERSION: i32 = 1;
+}
+
+/// One field of a book that an update changed, with values rendered as strings.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, AvroSchema)]
+pub struct FieldChange {
+    pub field: String,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+/// The book as it is after the update, plus the fields that changed. The ISBN
+/// is passed on as stored, since rows from before ISBN validation may hold
+/// values that do not parse.
+#[derive(Serialize, Deserialize, Builder, Clone, Debug, AvroSchema)]
+pub struct UpdatedBook {
     id: i32,
     title: String,
     isbn: String,
+    changes: Vec<FieldChange>,
+}
+
+impl EventPayload for UpdatedBook {
+    const SCHEMA_VERSION: i32 = 1;
+}
+
+/// The book as it was when it was deleted, with its ISBN as stored.
+#[derive(Serialize, Deserialize, Builder, Clone, Debug, AvroSchema)]
+pub struct DeletedBook {
+    id: i32,
+    title: String,
+    isbn: String,
+}
+
+impl EventPayload for DeletedBook {
+    const SCHEMA_VERSION: i32 = 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FieldChange, UpdatedBook, UpdatedBookBuilder};
+    use crate::events::envelope::EventEnvelope;
+    use apache_avro::{from_value, to_value, AvroSchema};
+
+    #[test]
+    fn test_updated_book_avro_roundtrip() {
+        let updated_book = UpdatedBookBuilder::default()
+            .id(1)
+            .title("Dune Messiah".to_string())
+            .isbn("9780441172719".to_string())
+            .changes(vec![FieldChange {
+                field: "title".to_string(),
+                old_value: "Dune".to_string(),
+                new_value: "Dune Messiah".to_string(),
+            }])
+            .build()
+            .unwrap();
+        let event = EventEnvelope::new("books_api", updated_book);
+
+        let value = to_value(&event)
+            .unwrap()
+            .resolve(&EventEnvelope::<UpdatedBook>::get_schema())
+            .unwrap();
+        let decoded = from_value::<EventEnvelope<UpdatedBook>>(&value).unwrap();
+        assert_eq!(decoded.event_id, event.event_id);
+        assert_eq!(decoded.payload.changes, event.payload.changes);
+    }
 }
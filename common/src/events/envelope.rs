@@ -0,0 +1,179 @@
+use apache_avro::schema::{
+    derive::AvroSchemaComponent, Name, Namespace, RecordField, RecordFieldOrder, Schema,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Version of an event payload's schema, carried in every envelope so
+/// consumers can tell which shape of `T` they are reading.
+pub trait EventPayload {
+    const SCHEMA_VERSION: i32;
+}
+
+/// Metadata shared by every event, wrapped around the domain payload.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EventEnvelope<T> {
+    /// Unique per event; consumers use it to drop redelivered duplicates.
+    pub event_id: String,
+    /// When the event happened, in milliseconds since the Unix epoch.
+    pub occurred_at: i64,
+    /// Name of the service that emitted the event.
+    pub producer: String,
+    pub schema_version: i32,
+    /// Ties the event to the request or event that caused it.
+    pub correlation_id: Option<String>,
+    pub payload: T,
+}
+
+impl<T: EventPayload> EventEnvelope<T> {
+    pub fn new(producer: impl Into<String>, payload: T) -> Self {
+        Self {
+            event_id: Uuid::new_v4().to_string(),
+            occurred_at: now_millis(),
+            producer: producer.into(),
+            schema_version: T::SCHEMA_VERSION,
+            correlation_id: None,
+            payload,
+        }
+    }
+}
+
+impl<T> EventEnvelope<T> {
+    pub fn with_correlation_id(mut self, correlation_id: Option<String>) -> Self {
+        self.correlation_id = correlation_id;
+        self
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Encoded as a record named after the payload, e.g. `CreatedBookEnvelope`,
+/// so each event type keeps its own name in the schema registry.
+impl<T: AvroSchemaComponent> AvroSchemaComponent for EventEnvelope<T> {
+    fn get_schema_in_ctxt(
+        named_schemas: &mut HashMap<Name, Schema>,
+        enclosing_namespace: &Namespace,
+    ) -> Schema {
+        let payload = T::get_schema_in_ctxt(named_schemas, enclosing_namespace);
+        let payload_name = match &payload {
+            Schema::Record { name, .. } | Schema::Ref { name } => name.clone(),
+            _ => Name {
+                name: "Event".to_owned(),
+                namespace: enclosing_namespace.clone(),
+            },
+        };
+        let name = Name {
+            name: format!("{}Envelope", payload_name.name),
+            namespace: payload_name.namespace,
+        };
+        if named_schemas.contains_key(&name) {
+            return Schema::Ref { name };
+        }
+        let field_schemas = [
+            ("event_id", Schema::String, None),
+            ("occurred_at", Schema::TimestampMillis, None),
+            ("producer", Schema::String, None),
+            ("schema_version", Schema::Int, None),
+            (
+                "correlation_id",
+                Option::<String>::get_schema_in_ctxt(named_schemas, enclosing_namespace),
+                Some(serde_json::Value::Null),
+            ),
+            ("payload", payload, None),
+        ];
+        let fields: Vec<RecordField> = field_schemas
+            .into_iter()
+            .enumerate()
+            .map(|(position, (field, schema, default))| RecordField {
+                name: field.to_owned(),
+                doc: None,
+                default,
+                schema,
+                order: RecordFieldOrder::Ascending,
+                position,
+            })
+            .collect();
+        let lookup: BTreeMap<String, usize> = fields
+            .iter()
+            .map(|field| (field.name.clone(), field.position))
+            .collect();
+        let schema = Schema::Record {
+            name: name.clone(),
+            aliases: None,
+            doc: None,
+            fields,
+            lookup,
+        };
+        named_schemas.insert(name, schema.clone());
+        schema
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventEnvelope, EventPayload};
+    use apache_avro::{from_value, to_value, AvroSchema, Schema};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, AvroSchema)]
+    struct Sample {
+        value: String,
+    }
+
+    impl EventPayload for Sample {
+        const SCHEMA_VERSION: i32 = 2;
+    }
+
+    #[test]
+    fn test_envelope_schema() {
+        let schema = EventEnvelope::<Sample>::get_schema();
+        match &schema {
+            Schema::Record { name, fields, .. } => {
+                assert_eq!(name.name, "SampleEnvelope");
+                let names: Vec<_> = fields.iter().map(|field| field.name.as_str()).collect();
+                assert_eq!(
+                    names,
+                    vec![
+                        "event_id",
+                        "occurred_at",
+                        "producer",
+                        "schema_version",
+                        "correlation_id",
+                        "payload"
+                    ]
+                );
+            }
+            other => panic!("expected a record schema, got {:?}", other),
+        }
+        assert!(Schema::parse_str(&serde_json::to_string(&schema).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_envelope_avro_roundtrip() {
+        let envelope = EventEnvelope::new(
+            "books_api",
+            Sample {
+                value: "payload".to_owned(),
+            },
+        )
+        .with_correlation_id(Some("request-1".to_owned()));
+        assert_eq!(envelope.schema_version, 2);
+        assert!(envelope.occurred_at > 0);
+
+        let value = to_value(&envelope)
+            .unwrap()
+            .resolve(&EventEnvelope::<Sample>::get_schema())
+            .unwrap();
+        assert_eq!(
+            from_value::<EventEnvelope<Sample>>(&value).unwrap(),
+            envelope
+        );
+    }
+}
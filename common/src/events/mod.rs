@@ -1,2 +1,26 @@
+use apache_avro::{AvroSchema, Schema};
+use constants::Topics;
+use dto::{CreatedBook, DeletedBook, UpdatedBook};
+use envelope::EventEnvelope;
+
 pub mod constants;
 pub mod dto;
+pub mod envelope;
+
+/// The envelope schema of every event, paired with the topic it is published to.
+pub fn event_schemas() -> [(Topics, Schema); 3] {
+    [
+        (
+            Topics::BookCreated,
+            EventEnvelope::<CreatedBook>::get_schema(),
+        ),
+        (
+            Topics::BookUpdated,
+            EventEnvelope::<UpdatedBook>::get_schema(),
+        ),
+        (
+            Topics::BookDeleted,
+            EventEnvelope::<DeletedBook>::get_schema(),
+        ),
+    ]
+}
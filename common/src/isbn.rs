@@ -0,0 +1,192 @@
+use apache_avro::schema::{derive::AvroSchemaComponent, Name, Namespace, Schema};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A checksum-validated ISBN, always held in its canonical ISBN-13 form
+/// without hyphens or spaces.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct Isbn(String);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum IsbnError {
+    #[error("ISBN must have 10 or 13 digits, found {0}")]
+    InvalidLength(usize),
+
+    #[error("ISBN contains invalid character '{0}'")]
+    InvalidCharacter(char),
+
+    #[error("ISBN-13 must start with 978 or 979")]
+    InvalidPrefix,
+
+    #[error("ISBN checksum does not match")]
+    InvalidChecksum,
+}
+
+impl Isbn {
+    pub fn parse(input: &str) -> Result<Self, IsbnError> {
+        let characters: Vec<char> = input
+            .chars()
+            .filter(|c| *c != '-' && !c.is_whitespace())
+            .collect();
+        match characters.len() {
+            10 => Self::parse_isbn10(&characters),
+            13 => Self::parse_isbn13(&characters),
+            length => Err(IsbnError::InvalidLength(length)),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    fn parse_isbn10(characters: &[char]) -> Result<Self, IsbnError> {
+        let mut digits = Vec::with_capacity(10);
+        for (position, character) in characters.iter().enumerate() {
+            let digit = match character {
+                'X' | 'x' if position == 9 => 10,
+                c => c.to_digit(10).ok_or(IsbnError::InvalidCharacter(*c))?,
+            };
+            digits.push(digit);
+        }
+        let checksum: u32 = digits
+            .iter()
+            .enumerate()
+            .map(|(position, digit)| (10 - position as u32) * digit)
+            .sum();
+        if checksum % 11 != 0 {
+            return Err(IsbnError::InvalidChecksum);
+        }
+        let mut isbn13_digits = vec![9, 7, 8];
+        isbn13_digits.extend_from_slice(&digits[..9]);
+        isbn13_digits.push(isbn13_check_digit(&isbn13_digits));
+        Ok(Self(to_string(&isbn13_digits)))
+    }
+
+    fn parse_isbn13(characters: &[char]) -> Result<Self, IsbnError> {
+        let digits = characters
+            .iter()
+            .map(|c| c.to_digit(10).ok_or(IsbnError::InvalidCharacter(*c)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if digits[..3] != [9, 7, 8] && digits[..3] != [9, 7, 9] {
+            return Err(IsbnError::InvalidPrefix);
+        }
+        if isbn13_check_digit(&digits[..12]) != digits[12] {
+            return Err(IsbnError::InvalidChecksum);
+        }
+        Ok(Self(to_string(&digits)))
+    }
+}
+
+fn isbn13_check_digit(digits: &[u32]) -> u32 {
+    let sum: u32 = digits
+        .iter()
+        .enumerate()
+        .map(|(position, digit)| if position % 2 == 0 { *digit } else { digit * 3 })
+        .sum();
+    (10 - sum % 10) % 10
+}
+
+fn to_string(digits: &[u32]) -> String {
+    digits
+        .iter()
+        .filter_map(|digit| char::from_digit(*digit, 10))
+        .collect()
+}
+
+impl FromStr for Isbn {
+    type Err = IsbnError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl TryFrom<String> for Isbn {
+    type Error = IsbnError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(&value)
+    }
+}
+
+impl From<Isbn> for String {
+    fn from(isbn: Isbn) -> Self {
+        isbn.0
+    }
+}
+
+impl fmt::Display for Isbn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Encoded as a plain Avro string so existing consumers keep reading it.
+impl AvroSchemaComponent for Isbn {
+    fn get_schema_in_ctxt(_: &mut HashMap<Name, Schema>, _: &Namespace) -> Schema {
+        Schema::String
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Isbn, IsbnError};
+    use apache_avro::{from_value, to_value, AvroSchema, Schema};
+
+    #[test]
+    fn test_parse_isbn13() {
+        let isbn = Isbn::parse("978-0-306-40615-7").unwrap();
+        assert_eq!(isbn.as_str(), "9780306406157");
+    }
+
+    #[test]
+    fn test_parse_isbn10_converts_to_isbn13() {
+        assert_eq!(
+            Isbn::parse("0-306-40615-2").unwrap().as_str(),
+            "9780306406157"
+        );
+        assert_eq!(
+            Isbn::parse("0 8044 2957 X").unwrap().as_str(),
+            "9780804429573"
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(Isbn::parse("ISBN"), Err(IsbnError::InvalidLength(4)));
+        assert_eq!(
+            Isbn::parse("978030640615X"),
+            Err(IsbnError::InvalidCharacter('X'))
+        );
+        assert_eq!(Isbn::parse("9770306406157"), Err(IsbnError::InvalidPrefix));
+        assert_eq!(
+            Isbn::parse("9780306406158"),
+            Err(IsbnError::InvalidChecksum)
+        );
+        assert_eq!(Isbn::parse("0306406153"), Err(IsbnError::InvalidChecksum));
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        let isbn = Isbn::parse("0-306-40615-2").unwrap();
+        assert_eq!(serde_json::to_string(&isbn).unwrap(), "\"9780306406157\"");
+        assert_eq!(
+            serde_json::from_str::<Isbn>("\"978-0-306-40615-7\"").unwrap(),
+            isbn
+        );
+        assert!(serde_json::from_str::<Isbn>("\"ISBN\"").is_err());
+    }
+
+    #[test]
+    fn test_avro_roundtrip() {
+        let isbn = Isbn::parse("9780306406157").unwrap();
+        assert_eq!(Isbn::get_schema(), Schema::String);
+        let value = to_value(&isbn).unwrap();
+        assert!(value.validate(&Isbn::get_schema()));
+        assert_eq!(from_value::<Isbn>(&value).unwrap(), isbn);
+    }
+}
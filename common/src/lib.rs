@@ -1 +1,5 @@
 pub mod events;
+pub mod isbn;
+pub mod metrics;
+pub mod settings;
+pub mod shutdown;
@@ -0,0 +1,60 @@
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use opentelemetry::metrics::MetricsError;
+use opentelemetry::sdk::export::metrics::aggregation;
+use opentelemetry::sdk::metrics::{controllers, processors, selectors};
+pub use opentelemetry_prometheus::PrometheusExporter;
+use prometheus::{Encoder, TextEncoder};
+use tracing::error;
+
+/// Histogram boundaries in seconds, shared by every latency instrument.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Installs a Prometheus-backed global meter provider.
+///
+/// Instruments are bound to the provider that was global when they were
+/// created, so call this before building anything that records metrics.
+pub fn init_prometheus_exporter() -> Result<PrometheusExporter, MetricsError> {
+    let controller = controllers::basic(processors::factory(
+        selectors::simple::histogram(LATENCY_BUCKETS),
+        aggregation::cumulative_temporality_selector(),
+    ))
+    .build();
+    opentelemetry_prometheus::exporter(controller).try_init()
+}
+
+/// Renders everything collected so far in the Prometheus text format.
+pub fn encode_metrics(exporter: &PrometheusExporter) -> Result<String, prometheus::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&exporter.registry().gather(), &mut buffer)?;
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+/// Axum handler for `/metrics`; expects the exporter as an [`Extension`].
+pub async fn metrics_handler(Extension(exporter): Extension<PrometheusExporter>) -> Response {
+    match encode_metrics(&exporter) {
+        Ok(body) => ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response(),
+        Err(e) => {
+            error!("Error encoding metrics: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_metrics, init_prometheus_exporter};
+    use opentelemetry::{global, Context, KeyValue};
+
+    #[test]
+    fn test_prometheus_exporter_encodes_instruments() {
+        let exporter = init_prometheus_exporter().unwrap();
+        let counter = global::meter("test").u64_counter("test.requests").init();
+        counter.add(&Context::current(), 2, &[KeyValue::new("route", "/")]);
+        let encoded = encode_metrics(&exporter).unwrap();
+        assert!(encoded.contains("test_requests_total"));
+    }
+}
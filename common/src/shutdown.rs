@@ -0,0 +1,43 @@
+use tokio::signal;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// Returns a token that is cancelled on SIGINT or SIGTERM.
+///
+/// Clones of the token are handed to every long-running task so they can
+/// stop taking new work and finish what is in flight.
+pub fn shutdown_token() -> CancellationToken {
+    let token = CancellationToken::new();
+    let signal_token = token.clone();
+    tokio::spawn(async move {
+        termination_signal().await;
+        info!("Shutdown signal received");
+        signal_token.cancel();
+    });
+    token
+}
+
+/// Completes when the process receives SIGINT or, on Unix, SIGTERM.
+pub async fn termination_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Unable to install SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Unable to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
@@ -1,28 +1,417 @@
-use apache_avro::from_value;
+use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
 use opentelemetry::{
     global,
+    propagation::Extractor,
     trace::{Span, Tracer},
-    Context,
+    Context, KeyValue,
 };
 use rdkafka::{
     config::RDKafkaLogLevel,
-    consumer::{CommitMode, Consumer, StreamConsumer},
-    ClientConfig, Message,
+    consumer::{
+        stream_consumer::StreamPartitionQueue, CommitMode, Consumer as _, ConsumerContext,
+        Rebalance, StreamConsumer,
+    },
+    error::KafkaError,
+    message::BorrowedMessage,
+    statistics::Statistics,
+    ClientConfig, ClientContext, Message, TopicPartitionList,
 };
-use schema_registry_converter::async_impl::{
-    easy_avro::EasyAvroDecoder, schema_registry::SrSettings,
-};
-use serde::Deserialize;
+use std::collections::HashMap;
 use std::fmt::Debug;
-use tokio::sync::mpsc::UnboundedSender;
-use tracing::{error, info};
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
+use thiserror::Error;
+use tokio::sync::mpsc::{unbounded_channel, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
+use crate::dead_letter::{
+    DeadLetterError, DeadLetterPublisher, FailureKind, FailurePolicy, MessageFailure,
+};
+use crate::format::{AvroSerde, Serde};
+use crate::handler::{IgnoreKey, MessageHandler, RetryPolicy};
+use crate::key::{decode_key, is_unkeyed, KeyFormat, MessageKey, Unkeyed};
+use crate::metrics::{ConsumerMetrics, ConsumerStatsContext};
+use crate::producer::{CORRELATION_ID_HEADER, EVENT_ID_HEADER};
 use crate::util::HeaderExtractor;
 
-pub struct KafkaConsumer {
-    consumer: StreamConsumer,
-    avro_decoder: EasyAvroDecoder,
+const DEFAULT_MAX_CONCURRENCY: usize = 16;
+
+type PartitionQueue = StreamPartitionQueue<KafkaConsumerContext>;
+
+/// Partitions of the listened-to topic whose assignment changed in a rebalance.
+enum PartitionChange {
+    /// Each newly assigned partition with its queue, split off before
+    /// anything was fetched for it.
+    Assigned(Vec<(i32, PartitionQueue)>),
+    Revoked(Vec<i32>),
+}
+
+/// Where a partitioned consumer wants to hear about rebalances of `topic`.
+struct PartitionListener {
+    consumer: Weak<StreamConsumer<KafkaConsumerContext>>,
     topic: String,
+    sender: UnboundedSender<PartitionChange>,
+}
+
+/// Collects librdkafka statistics and tells a partitioned consumer about
+/// rebalances, which are served while the main queue is polled.
+#[derive(Default)]
+pub(crate) struct KafkaConsumerContext {
+    stats: ConsumerStatsContext,
+    partition_listener: Mutex<Option<PartitionListener>>,
+}
+
+impl KafkaConsumerContext {
+    /// Reports changes to `topic`'s partitions from the next rebalance on;
+    /// replaces any earlier listener.
+    fn listen_for_partition_changes(
+        &self,
+        consumer: &Arc<StreamConsumer<KafkaConsumerContext>>,
+        topic: &str,
+    ) -> UnboundedReceiver<PartitionChange> {
+        let (sender, receiver) = unbounded_channel();
+        *self
+            .partition_listener
+            .lock()
+            .expect("partition listener lock poisoned") = Some(PartitionListener {
+            consumer: Arc::downgrade(consumer),
+            topic: topic.to_owned(),
+            sender,
+        });
+        receiver
+    }
+}
+
+impl ClientContext for KafkaConsumerContext {
+    fn stats(&self, statistics: Statistics) {
+        self.stats.stats(statistics)
+    }
+}
+
+impl ConsumerContext for KafkaConsumerContext {
+    /// Splits off the queues of assigned partitions before librdkafka starts
+    /// fetching them, so none of their messages reach the main queue.
+    fn pre_rebalance(&self, rebalance: &Rebalance<'_>) {
+        let Ok(listener) = self.partition_listener.lock() else {
+            return;
+        };
+        // Once partitioned consumption stops, later assignments stay on the main queue.
+        let Some(listener) = listener
+            .as_ref()
+            .filter(|listener| !listener.sender.is_closed())
+        else {
+            return;
+        };
+        let partitions = |list: &TopicPartitionList| {
+            list.elements_for_topic(&listener.topic)
+                .iter()
+                .map(|element| element.partition())
+                .collect::<Vec<_>>()
+        };
+        let change = match rebalance {
+            Rebalance::Assign(assigned) => {
+                // Gone only while the consumer is being dropped.
+                let Some(consumer) = listener.consumer.upgrade() else {
+                    return;
+                };
+                PartitionChange::Assigned(
+                    partitions(assigned)
+                        .into_iter()
+                        .filter_map(|partition| {
+                            consumer
+                                .split_partition_queue(&listener.topic, partition)
+                                .map(|queue| (partition, queue))
+                        })
+                        .collect(),
+                )
+            }
+            Rebalance::Revoke(revoked) => PartitionChange::Revoked(partitions(revoked)),
+            Rebalance::Error(e) => {
+                warn!("Rebalance failed: {}", e);
+                return;
+            }
+        };
+        let _ = listener.sender.send(change);
+    }
+}
+
+/// Consumes one topic, decoding payloads with `S`. Avro keys are always
+/// decoded with the consumer's [`AvroSerde`].
+pub struct KafkaConsumer<S = AvroSerde> {
+    consumer: Arc<StreamConsumer<KafkaConsumerContext>>,
+    avro_serde: AvroSerde,
+    serde: S,
+    topic: String,
+    key_format: KeyFormat,
+    metrics: ConsumerMetrics,
+    failure_policy: FailurePolicy,
+    retry_policy: RetryPolicy,
+    max_concurrency: usize,
+    dead_letter_publisher: DeadLetterPublisher,
+}
+
+#[derive(Error, Debug)]
+pub enum KafkaConsumerError {
+    #[error("Kafka error: {0}")]
+    Kafka(#[from] KafkaError),
+
+    #[error("Halted at {topic}[{partition}]@{offset} after {} failure: {}", failure.kind, failure.reason)]
+    Halted {
+        topic: String,
+        partition: i32,
+        offset: i64,
+        failure: MessageFailure,
+    },
+
+    #[error("Unable to dead-letter message: {0}")]
+    DeadLetter(#[from] DeadLetterError),
+}
+
+/// Consumption entry points shared by [`KafkaConsumer`] and
+/// `memory::InMemoryConsumer` (behind the `test-util` feature).
+#[async_trait]
+pub trait Consumer: Send + Sync {
+    /// How payloads are decoded.
+    type Serde: Send + Sync;
+
+    /// Consumes until the stream fails or `shutdown` is cancelled, handing
+    /// `handler` each record's key and payload and committing the record only
+    /// after `handler` acknowledges it. On shutdown the current offsets are
+    /// committed before returning.
+    ///
+    /// Handler errors are retried per the [`RetryPolicy`], and schema registry
+    /// lookups that may succeed later are retried until `shutdown`. Records
+    /// that cannot be decoded, or whose handler keeps failing, are dealt with
+    /// according to the [`FailurePolicy`]; with [`FailurePolicy::Halt`] this
+    /// returns [`KafkaConsumerError::Halted`] without committing the record.
+    async fn consume_keyed_with<K, V, H>(
+        &self,
+        handler: &H,
+        shutdown: CancellationToken,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        K: MessageKey,
+        V: Clone + Debug + Send + 'static,
+        H: MessageHandler<(K, V)>,
+        Self::Serde: Serde<V>;
+
+    /// Like [`Consumer::consume_keyed_with`], but may handle different
+    /// partitions concurrently. Messages within a partition are still handled
+    /// and committed in order.
+    async fn consume_keyed_partitioned<K, V, H>(
+        &self,
+        handler: &H,
+        shutdown: CancellationToken,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        K: MessageKey,
+        V: Clone + Debug + Send + 'static,
+        H: MessageHandler<(K, V)>,
+        Self::Serde: Serde<V>;
+
+    /// Like [`Consumer::consume_keyed_with`], but hands `handler` only the
+    /// payload and ignores keys.
+    async fn consume_with<T, H>(
+        &self,
+        handler: &H,
+        shutdown: CancellationToken,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        T: Clone + Debug + Send + 'static,
+        H: MessageHandler<T>,
+        Self::Serde: Serde<T>,
+    {
+        self.consume_keyed_with::<Unkeyed, T, _>(&IgnoreKey(handler), shutdown)
+            .await
+    }
+
+    /// Like [`Consumer::consume_keyed_partitioned`], but hands `handler` only
+    /// the payload and ignores keys.
+    async fn consume_partitioned<T, H>(
+        &self,
+        handler: &H,
+        shutdown: CancellationToken,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        T: Clone + Debug + Send + 'static,
+        H: MessageHandler<T>,
+        Self::Serde: Serde<T>,
+    {
+        self.consume_keyed_partitioned::<Unkeyed, T, _>(&IgnoreKey(handler), shutdown)
+            .await
+    }
+
+    /// Forwards every decoded message to the bounded `sender`, applying
+    /// backpressure while the channel is full. Offsets are committed once the
+    /// message is in the channel; use [`Consumer::consume_with`] to commit
+    /// only after processing.
+    async fn consume<T: Clone + Debug + Send + 'static>(
+        &self,
+        sender: Sender<T>,
+        shutdown: CancellationToken,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        Self::Serde: Serde<T>,
+    {
+        self.consume_with(&sender, shutdown).await
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> Consumer for KafkaConsumer<S> {
+    type Serde = S;
+
+    async fn consume_keyed_with<K, V, H>(
+        &self,
+        handler: &H,
+        shutdown: CancellationToken,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        K: MessageKey,
+        V: Clone + Debug + Send + 'static,
+        H: MessageHandler<(K, V)>,
+        S: Serde<V>,
+    {
+        self.consumer.subscribe(&[&self.topic])?;
+
+        loop {
+            if handler.is_saturated() {
+                self.wait_for_handler(handler, &shutdown, None).await?;
+            }
+            let message = tokio::select! {
+                biased;
+
+                _ = shutdown.cancelled() => {
+                    info!("Stopping consumer for topic {}", self.topic);
+                    if let Err(e) = self.consumer.commit_consumer_state(CommitMode::Sync) {
+                        warn!("Unable to commit offsets on shutdown: {}", e);
+                    }
+                    break;
+                }
+                message = self.consumer.recv() => message?,
+            };
+            self.process_message(handler, &message, &shutdown).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads each assigned partition of the topic from its own queue and
+    /// handles partitions concurrently, with at most `max_concurrency`
+    /// messages in flight across all partitions. Queues are split off as
+    /// partitions are assigned, before any of their messages are fetched, and
+    /// dropped when they are revoked. While the handler is saturated, each
+    /// partition is paused until it has room again.
+    ///
+    /// When one partition fails, the others finish the message they are
+    /// handling and stop; the first error is returned once all have stopped.
+    async fn consume_keyed_partitioned<K, V, H>(
+        &self,
+        handler: &H,
+        shutdown: CancellationToken,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        K: MessageKey,
+        V: Clone + Debug + Send + 'static,
+        H: MessageHandler<(K, V)>,
+        S: Serde<V>,
+    {
+        let mut partition_changes = self
+            .consumer
+            .context()
+            .listen_for_partition_changes(&self.consumer, &self.topic);
+        self.consumer.subscribe(&[&self.topic])?;
+        info!(
+            "Consuming partitions of {} with up to {} messages in flight",
+            self.topic, self.max_concurrency
+        );
+
+        let stop = shutdown.child_token();
+        let first_error = Mutex::new(None);
+        let fail = |e: KafkaConsumerError| {
+            let mut first_error = first_error.lock().expect("first error lock poisoned");
+            first_error.get_or_insert(e);
+            stop.cancel();
+        };
+        let in_flight = Semaphore::new(self.max_concurrency);
+        let partition_loop =
+            |partition: i32, queue: PartitionQueue, partition_stop: CancellationToken| {
+                let in_flight = &in_flight;
+                async move {
+                    self.consume_queue(handler, in_flight, &partition_stop, Some(partition), || {
+                        queue.recv()
+                    })
+                    .await
+                }
+            };
+        let partitions = async {
+            let mut partition_stops: HashMap<i32, CancellationToken> = HashMap::new();
+            let mut partition_loops = FuturesUnordered::new();
+            loop {
+                tokio::select! {
+                    biased;
+
+                    _ = stop.cancelled() => break,
+                    Some(result) = partition_loops.next(), if !partition_loops.is_empty() => {
+                        if let Err(e) = result {
+                            fail(e);
+                        }
+                    }
+                    Some(change) = partition_changes.recv() => match change {
+                        PartitionChange::Assigned(assigned) => {
+                            for (partition, queue) in assigned {
+                                // A partition loop of an earlier assignment never reads again.
+                                if let Some(previous) = partition_stops.remove(&partition) {
+                                    previous.cancel();
+                                }
+                                info!("Assigned partition {} of {}", partition, self.topic);
+                                let partition_stop = stop.child_token();
+                                partition_stops.insert(partition, partition_stop.clone());
+                                partition_loops.push(partition_loop(partition, queue, partition_stop));
+                            }
+                        }
+                        PartitionChange::Revoked(revoked) => {
+                            for partition in revoked {
+                                if let Some(partition_stop) = partition_stops.remove(&partition) {
+                                    info!("Revoked partition {} of {}", partition, self.topic);
+                                    partition_stop.cancel();
+                                }
+                            }
+                        }
+                    },
+                }
+            }
+            // Let every partition finish the message it is handling.
+            while let Some(result) = partition_loops.next().await {
+                if let Err(e) = result {
+                    fail(e);
+                }
+            }
+        };
+        // Rebalances are served from the main queue. Partition queues are
+        // split off before fetching starts, so it holds no messages of the
+        // topic and handling one here cannot overtake a partition loop.
+        let main_loop = async {
+            if let Err(e) = self
+                .consume_queue(handler, &in_flight, &stop, None, || self.consumer.recv())
+                .await
+            {
+                fail(e);
+            }
+        };
+        tokio::join!(partitions, main_loop);
+
+        info!("Stopping consumer for topic {}", self.topic);
+        if let Err(e) = self.consumer.commit_consumer_state(CommitMode::Sync) {
+            warn!("Unable to commit offsets on shutdown: {}", e);
+        }
+        match first_error.into_inner().expect("first error lock poisoned") {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
 }
 
 impl KafkaConsumer {
@@ -32,94 +421,398 @@ impl KafkaConsumer {
         group_id: String,
         topic: String,
     ) -> Self {
-        let consumer: StreamConsumer = ClientConfig::new()
+        let context = KafkaConsumerContext::default();
+        let metrics = ConsumerMetrics::new(&context.stats);
+        let dead_letter_publisher = DeadLetterPublisher::new(&bootstrap_servers);
+        let consumer: StreamConsumer<KafkaConsumerContext> = ClientConfig::new()
             .set("group.id", group_id)
             .set("bootstrap.servers", bootstrap_servers)
             .set("session.timeout.ms", "6000")
             .set("enable.auto.commit", "false")
+            .set("enable.auto.offset.store", "false")
             .set("auto.offset.reset", "earliest")
+            .set("statistics.interval.ms", "5000")
+            // Bound librdkafka's prefetch buffer so paused consumers hold little in memory.
+            .set("queued.max.messages.kbytes", "8192")
             .set_log_level(RDKafkaLogLevel::Debug)
-            .create()
+            .create_with_context(context)
             .expect("Consumer creation error");
-        let sr_settings = SrSettings::new(schema_registry_url);
-        let avro_decoder = EasyAvroDecoder::new(sr_settings);
+        let avro_serde = AvroSerde::new(schema_registry_url);
         Self {
-            consumer,
+            consumer: Arc::new(consumer),
+            failure_policy: FailurePolicy::dead_letter_for(&topic),
+            retry_policy: RetryPolicy::default(),
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            key_format: KeyFormat::default(),
             topic,
-            avro_decoder,
+            serde: avro_serde.clone(),
+            avro_serde,
+            metrics,
+            dead_letter_publisher,
+        }
+    }
+}
+
+impl<S> KafkaConsumer<S> {
+    /// Decodes payloads with `serde` instead.
+    pub fn with_serde<S2>(self, serde: S2) -> KafkaConsumer<S2> {
+        KafkaConsumer {
+            consumer: self.consumer,
+            avro_serde: self.avro_serde,
+            serde,
+            topic: self.topic,
+            key_format: self.key_format,
+            metrics: self.metrics,
+            failure_policy: self.failure_policy,
+            retry_policy: self.retry_policy,
+            max_concurrency: self.max_concurrency,
+            dead_letter_publisher: self.dead_letter_publisher,
         }
     }
 
-    pub async fn consume<T: Clone + Debug + for<'a> Deserialize<'a>>(
+    /// Replaces the default policy of dead-lettering to `<topic>.dlq`.
+    pub fn with_failure_policy(mut self, failure_policy: FailurePolicy) -> Self {
+        self.failure_policy = failure_policy;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// How the topic's keys are encoded; [`KeyFormat::String`] by default.
+    pub fn with_key_format(mut self, key_format: KeyFormat) -> Self {
+        self.key_format = key_format;
+        self
+    }
+
+    /// Caps how many messages [`Consumer::consume_partitioned`] handles at once.
+    pub fn with_max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = max_concurrency.max(1);
+        self
+    }
+
+    /// Handles what `recv` returns from the queue of `partition`, or from the
+    /// main queue for `None`. A saturated handler pauses only that partition;
+    /// the main queue is never paused as it also serves rebalances.
+    async fn consume_queue<'q, K, V, H, F, R>(
         &self,
-        sender: UnboundedSender<T>,
-    ) {
-        self.consumer
-            .subscribe(&[&self.topic])
-            .expect("Can't subscribe to specific topics");
-
-        while let Ok(message) = self.consumer.recv().await {
-            let context = if let Some(headers) = message.headers() {
-                global::get_text_map_propagator(|propagator| {
-                    propagator.extract(&HeaderExtractor(&headers))
-                })
-            } else {
-                Context::current()
+        handler: &H,
+        in_flight: &Semaphore,
+        shutdown: &CancellationToken,
+        partition: Option<i32>,
+        recv: F,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        K: MessageKey,
+        V: Clone + Debug + Send + 'static,
+        H: MessageHandler<(K, V)>,
+        S: Serde<V>,
+        F: Fn() -> R,
+        R: Future<Output = Result<BorrowedMessage<'q>, KafkaError>>,
+    {
+        let label = match partition {
+            Some(partition) => format!("partition {}", partition),
+            None => "main queue".to_owned(),
+        };
+        loop {
+            if partition.is_some() && handler.is_saturated() {
+                self.wait_for_handler(handler, shutdown, partition).await?;
+            }
+            let message = tokio::select! {
+                biased;
+
+                _ = shutdown.cancelled() => return Ok(()),
+                message = recv() => message?,
             };
+            let _permit = in_flight
+                .acquire()
+                .await
+                .expect("in-flight semaphore is never closed");
+            self.process_message(handler, &message, shutdown)
+                .await
+                .map_err(|e| {
+                    error!("Stopping {} of {}: {}", label, self.topic, e);
+                    e
+                })?;
+        }
+    }
+
+    /// Decodes and handles one message, applying the failure policy, and
+    /// commits its offset once it has been dealt with. A message still waiting
+    /// for the schema registry when `shutdown` is cancelled is left uncommitted.
+    async fn process_message<K, V, H>(
+        &self,
+        handler: &H,
+        message: &BorrowedMessage<'_>,
+        shutdown: &CancellationToken,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        K: MessageKey,
+        V: Clone + Debug + Send + 'static,
+        H: MessageHandler<(K, V)>,
+        S: Serde<V>,
+    {
+        let context = if let Some(headers) = message.headers() {
+            global::get_text_map_propagator(|propagator| {
+                propagator.extract(&HeaderExtractor(headers))
+            })
+        } else {
+            Context::current()
+        };
 
-            let mut span =
-                global::tracer("consumer").start_with_context("consume_payload", &context);
-
-            // let key = match self.avro_decoder.decode(message.key()).await {
-            //     Ok(v) => v.value,
-            //     Err(e) => {
-            //         warn!(
-            //             "encountered error, key probably was not avro encoded: {}",
-            //             e
-            //         );
-            //         match String::from_utf8(Vec::from(message.key().unwrap())) {
-            //             Ok(s) => Value::String(s),
-            //             Err(_) => {
-            //                 println!("It was not a String either :(");
-            //                 Value::Bytes(Vec::from(message.key().unwrap()))
-            //             }
-            //         }
-            //     }
-            // };
-            let value_result = match self.avro_decoder.decode(message.payload()).await {
-                Ok(v) => Ok(v.value),
-                Err(e) => {
-                    error!("Error getting value: {}", e);
-                    Err(e)
+        let mut span = global::tracer("consumer").start_with_context("consume_payload", &context);
+        if let Some(headers) = message.headers() {
+            let extractor = HeaderExtractor(headers);
+            for key in [EVENT_ID_HEADER, CORRELATION_ID_HEADER] {
+                if let Some(value) = extractor.get(key) {
+                    span.set_attribute(KeyValue::new(key, value.to_owned()));
                 }
-            };
+            }
+        }
 
-            if let Ok(value) = value_result {
-                if let Ok(deserialized_payload) = from_value::<T>(&value) {
-                    info!(
-                                    "key: '{:?}', payload: '{:?}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
-                                    message.key(),
-                                    deserialized_payload,
-                                    message.topic(),
-                                    message.partition(),
-                                    message.offset(),
-                                    message.timestamp()
-                                );
-                    if let Err(e) = sender.send(deserialized_payload) {
-                        error!("Error while sending via channel: {}", e);
-                    } else {
-                        info!("Message consumed successfully");
+        let decoded = self
+            .retry_policy
+            .decode(shutdown, || async {
+                let key = self.decode_key::<K>(message).await?;
+                let payload = self.serde.deserialize(message.payload()).await?;
+                Ok((key, payload))
+            })
+            .await;
+        let outcome = match decoded {
+            Err(failure) if failure.is_retriable() => {
+                info!(
+                    "Leaving {}[{}]@{} uncommitted on shutdown: {}",
+                    message.topic(),
+                    message.partition(),
+                    message.offset(),
+                    failure.reason
+                );
+                return Ok(());
+            }
+            Ok((key, payload)) => {
+                info!(
+                    "key: '{:?}', payload: '{:?}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
+                    key,
+                    payload,
+                    message.topic(),
+                    message.partition(),
+                    message.offset(),
+                    message.timestamp()
+                );
+                self.retry_policy.handle(handler, (key, payload)).await
+            }
+            Err(failure) => Err(failure),
+        };
+        match outcome {
+            Ok(()) => {
+                info!("Message consumed successfully");
+                self.metrics.record(message.topic(), true);
+            }
+            Err(failure) => {
+                error!(
+                    "Unable to {} message at {}[{}]@{}: {}",
+                    failure.kind,
+                    message.topic(),
+                    message.partition(),
+                    message.offset(),
+                    failure.reason
+                );
+                self.metrics.record(message.topic(), false);
+                match &self.failure_policy {
+                    FailurePolicy::DeadLetter(dead_letter_topic) if !failure.is_retriable() => {
+                        self.dead_letter_publisher
+                            .publish(dead_letter_topic, message, &failure)
+                            .await?
+                    }
+                    _ => {
+                        return Err(KafkaConsumerError::Halted {
+                            topic: message.topic().to_owned(),
+                            partition: message.partition(),
+                            offset: message.offset(),
+                            failure,
+                        })
                     }
-                } else {
-                    error!("Error while deserializing message payload");
                 }
-            } else {
-                error!("Error while deserializing message payload");
             }
-            self.consumer
-                .commit_message(&message, CommitMode::Async)
-                .unwrap();
-            span.end();
         }
+        // Offsets are only stored once a record is dealt with, so the commit
+        // on shutdown never skips a record that halted the consumer. Both fail
+        // if the partition was revoked meanwhile; its new owner redelivers it.
+        if let Err(e) = self
+            .consumer
+            .store_offset_from_message(message)
+            .and_then(|()| self.consumer.commit_message(message, CommitMode::Async))
+        {
+            warn!(
+                "Unable to commit {}[{}]@{}: {}",
+                message.topic(),
+                message.partition(),
+                message.offset(),
+                e
+            );
+        }
+        span.end();
+        Ok(())
+    }
+
+    /// Pauses fetching `partition`, or every assigned partition for `None`,
+    /// until `handler` has capacity again so that neither the consumer nor
+    /// librdkafka buffers an unbounded backlog. Gives up early if shutdown
+    /// begins.
+    async fn wait_for_handler<T, H>(
+        &self,
+        handler: &H,
+        shutdown: &CancellationToken,
+        partition: Option<i32>,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        T: Send + 'static,
+        H: MessageHandler<T>,
+    {
+        let paused = self.partitions(partition)?;
+        self.consumer.pause(&paused)?;
+        info!(
+            "Handler saturated, paused {} partitions of {}",
+            paused.count(),
+            self.topic
+        );
+        tokio::select! {
+            _ = shutdown.cancelled() => {},
+            _ = handler.ready() => {},
+        }
+        // A rebalance may have run while paused; resume what is owned now.
+        let resumed = self.partitions(partition)?;
+        match self.consumer.resume(&resumed) {
+            Ok(()) => info!(
+                "Handler drained, resumed {} partitions of {}",
+                resumed.count(),
+                self.topic
+            ),
+            Err(e) => warn!("Unable to resume partitions of {}: {}", self.topic, e),
+        }
+        Ok(())
+    }
+
+    /// Just `partition` of the topic, or the whole current assignment for `None`.
+    fn partitions(&self, partition: Option<i32>) -> Result<TopicPartitionList, KafkaError> {
+        match partition {
+            Some(partition) => {
+                let mut partitions = TopicPartitionList::new();
+                partitions.add_partition(&self.topic, partition);
+                Ok(partitions)
+            }
+            None => self.consumer.assignment(),
+        }
+    }
+
+    /// Avro keys are decoded through the schema registry, like payloads.
+    async fn decode_key<K: MessageKey>(
+        &self,
+        message: &BorrowedMessage<'_>,
+    ) -> Result<K, MessageFailure> {
+        if self.key_format != KeyFormat::Avro || is_unkeyed::<K>() {
+            return decode_key(self.key_format, message.key()).map_err(|e| MessageFailure {
+                kind: FailureKind::Decode,
+                reason: e.to_string(),
+            });
+        }
+        self.avro_serde
+            .decode(message.key())
+            .await
+            .map_err(|failure| MessageFailure {
+                reason: format!("Key: {}", failure.reason),
+                ..failure
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KafkaConsumer, PartitionChange};
+    use crate::handler::IgnoreKey;
+    use crate::key::Unkeyed;
+    use rdkafka::consumer::{Consumer as _, ConsumerContext, Rebalance};
+    use rdkafka::TopicPartitionList;
+    use std::future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::sync::{mpsc, Semaphore};
+    use tokio::time::timeout;
+    use tokio_util::sync::CancellationToken;
+
+    fn offline_consumer() -> KafkaConsumer {
+        KafkaConsumer::new(
+            "localhost:9092".to_string(),
+            "http://localhost:8081".to_string(),
+            "group".to_string(),
+            "books".to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_partition_queues_are_split_within_the_rebalance() {
+        let consumer = offline_consumer();
+        let context = consumer.consumer.context();
+        let mut changes = context.listen_for_partition_changes(&consumer.consumer, "books");
+        let mut assigned = TopicPartitionList::new();
+        assigned.add_partition("books", 0);
+        assigned.add_partition("books", 1);
+        assigned.add_partition("authors", 0);
+
+        // The queues are handed over before librdkafka applies the assignment
+        // and starts fetching, so the main queue never sees these partitions.
+        context.pre_rebalance(&Rebalance::Assign(&assigned));
+        let Ok(PartitionChange::Assigned(queues)) = changes.try_recv() else {
+            panic!("expected the assigned partitions");
+        };
+        let partitions: Vec<i32> = queues.iter().map(|(partition, _)| *partition).collect();
+        assert_eq!(partitions, vec![0, 1]);
+
+        let mut revoked = TopicPartitionList::new();
+        revoked.add_partition("books", 1);
+        context.pre_rebalance(&Rebalance::Revoke(&revoked));
+        let Ok(PartitionChange::Revoked(partitions)) = changes.try_recv() else {
+            panic!("expected the revoked partition");
+        };
+        assert_eq!(partitions, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_saturated_handler_pauses_partition_queue() {
+        let consumer = offline_consumer();
+        let (sender, mut receiver) = mpsc::channel::<String>(1);
+        sender.send("pending".to_string()).await.unwrap();
+        let handler = IgnoreKey(&sender);
+        let in_flight = Semaphore::new(1);
+        let shutdown = CancellationToken::new();
+        let receives = AtomicUsize::new(0);
+        let consuming = consumer.consume_queue::<Unkeyed, String, _, _, _>(
+            &handler,
+            &in_flight,
+            &shutdown,
+            Some(0),
+            || {
+                receives.fetch_add(1, Ordering::SeqCst);
+                future::pending()
+            },
+        );
+        tokio::pin!(consuming);
+
+        // Nothing is read from the partition while the handler has no room.
+        assert!(timeout(Duration::from_millis(100), &mut consuming)
+            .await
+            .is_err());
+        assert_eq!(receives.load(Ordering::SeqCst), 0);
+
+        assert_eq!(receiver.recv().await.as_deref(), Some("pending"));
+        assert!(timeout(Duration::from_millis(100), &mut consuming)
+            .await
+            .is_err());
+        assert_eq!(receives.load(Ordering::SeqCst), 1);
+
+        shutdown.cancel();
+        consuming.await.unwrap();
     }
 }
@@ -0,0 +1,286 @@
+use rdkafka::{
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    error::KafkaError,
+    message::{BorrowedMessage, Header, Headers, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    ClientConfig, Message,
+};
+use std::fmt;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{info, warn};
+
+pub const ORIGINAL_TOPIC_HEADER: &str = "dlq.original.topic";
+pub const ORIGINAL_PARTITION_HEADER: &str = "dlq.original.partition";
+pub const ORIGINAL_OFFSET_HEADER: &str = "dlq.original.offset";
+pub const ERROR_KIND_HEADER: &str = "dlq.error.kind";
+pub const ERROR_MESSAGE_HEADER: &str = "dlq.error.message";
+
+const DLQ_HEADERS: [&str; 5] = [
+    ORIGINAL_TOPIC_HEADER,
+    ORIGINAL_PARTITION_HEADER,
+    ORIGINAL_OFFSET_HEADER,
+    ERROR_KIND_HEADER,
+    ERROR_MESSAGE_HEADER,
+];
+
+/// What `KafkaConsumer` does with a message it cannot decode, deserialize or
+/// handle. Records failing with [`FailureKind::Unavailable`] are retried until
+/// shutdown instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Publish the raw record to this topic, then commit and carry on.
+    DeadLetter(String),
+    /// Stop consuming without committing the offending record.
+    Halt,
+}
+
+impl FailurePolicy {
+    /// Dead-letters to `<topic>.dlq`.
+    pub fn dead_letter_for(topic: &str) -> Self {
+        FailurePolicy::DeadLetter(format!("{}.dlq", topic))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The payload was malformed or its schema was rejected by the registry.
+    Decode,
+    /// The Avro value did not match the expected type.
+    Deserialize,
+    /// The message handler kept failing after all retries.
+    Handler,
+    /// The schema registry could not be reached. Such records are retried
+    /// and never dead-lettered, since nothing is wrong with them.
+    Unavailable,
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FailureKind::Decode => "decode",
+            FailureKind::Deserialize => "deserialize",
+            FailureKind::Handler => "handle",
+            FailureKind::Unavailable => "fetch the schema of",
+        })
+    }
+}
+
+/// Why a single record could not be handed to the application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageFailure {
+    pub kind: FailureKind,
+    pub reason: String,
+}
+
+impl MessageFailure {
+    /// Whether the same record may be handled successfully later.
+    pub fn is_retriable(&self) -> bool {
+        self.kind == FailureKind::Unavailable
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DeadLetterError {
+    #[error("Kafka error: {0}")]
+    Kafka(#[from] KafkaError),
+
+    #[error("Dead-lettered message at offset {offset} has no {ORIGINAL_TOPIC_HEADER} header")]
+    MissingOriginalTopic { offset: i64 },
+}
+
+/// Publishes undecodable records, byte for byte, to a dead-letter topic.
+#[derive(Clone)]
+pub(crate) struct DeadLetterPublisher {
+    producer: FutureProducer,
+}
+
+impl DeadLetterPublisher {
+    pub(crate) fn new(bootstrap_servers: &str) -> Self {
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", bootstrap_servers)
+            .set("message.timeout.ms", "5000")
+            .create()
+            .expect("Dead-letter producer creation error");
+        Self { producer }
+    }
+
+    pub(crate) async fn publish(
+        &self,
+        dead_letter_topic: &str,
+        message: &BorrowedMessage<'_>,
+        failure: &MessageFailure,
+    ) -> Result<(), DeadLetterError> {
+        let partition = message.partition().to_string();
+        let offset = message.offset().to_string();
+        let kind = failure.kind.to_string();
+        let mut headers = copy_headers(message.headers(), &[]);
+        for (key, value) in [
+            (ORIGINAL_TOPIC_HEADER, message.topic()),
+            (ORIGINAL_PARTITION_HEADER, partition.as_str()),
+            (ORIGINAL_OFFSET_HEADER, offset.as_str()),
+            (ERROR_KIND_HEADER, kind.as_str()),
+            (ERROR_MESSAGE_HEADER, failure.reason.as_str()),
+        ] {
+            headers = headers.insert(Header {
+                key,
+                value: Some(value),
+            });
+        }
+        let mut record: FutureRecord<'_, [u8], [u8]> =
+            FutureRecord::to(dead_letter_topic).headers(headers);
+        if let Some(key) = message.key() {
+            record = record.key(key);
+        }
+        if let Some(payload) = message.payload() {
+            record = record.payload(payload);
+        }
+        self.producer
+            .send(record, Duration::from_secs(5))
+            .await
+            .map_err(|(e, _)| e)?;
+        warn!(
+            "Dead-lettered {} message from {}[{}]@{} to {}: {}",
+            failure.kind,
+            message.topic(),
+            message.partition(),
+            message.offset(),
+            dead_letter_topic,
+            failure.reason
+        );
+        Ok(())
+    }
+}
+
+/// Moves dead-lettered records back onto the topic they came from, e.g. after
+/// a consumer fix has been deployed.
+pub struct DeadLetterReplayer {
+    consumer: StreamConsumer,
+    producer: FutureProducer,
+    dead_letter_topic: String,
+    idle_timeout: Duration,
+}
+
+impl DeadLetterReplayer {
+    pub fn new(bootstrap_servers: String, group_id: String, dead_letter_topic: String) -> Self {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("group.id", group_id)
+            .set("bootstrap.servers", &bootstrap_servers)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .expect("Consumer creation error");
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", bootstrap_servers)
+            .set("message.timeout.ms", "5000")
+            .create()
+            .expect("Producer creation error");
+        Self {
+            consumer,
+            producer,
+            dead_letter_topic,
+            idle_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Replays until no record arrives for the idle timeout, committing each
+    /// record only after it has been re-published. Returns the number replayed.
+    pub async fn replay(&self) -> Result<usize, DeadLetterError> {
+        self.consumer.subscribe(&[&self.dead_letter_topic])?;
+        let mut replayed = 0;
+        while let Ok(message) = tokio::time::timeout(self.idle_timeout, self.consumer.recv()).await
+        {
+            let message = message?;
+            let original_topic = header_value(message.headers(), ORIGINAL_TOPIC_HEADER).ok_or(
+                DeadLetterError::MissingOriginalTopic {
+                    offset: message.offset(),
+                },
+            )?;
+            let mut record: FutureRecord<'_, [u8], [u8]> = FutureRecord::to(&original_topic)
+                .headers(copy_headers(message.headers(), &DLQ_HEADERS));
+            if let Some(key) = message.key() {
+                record = record.key(key);
+            }
+            if let Some(payload) = message.payload() {
+                record = record.payload(payload);
+            }
+            self.producer
+                .send(record, Duration::from_secs(5))
+                .await
+                .map_err(|(e, _)| e)?;
+            self.consumer.commit_message(&message, CommitMode::Sync)?;
+            replayed += 1;
+        }
+        info!(
+            "Replayed {} messages from {}",
+            replayed, self.dead_letter_topic
+        );
+        Ok(replayed)
+    }
+}
+
+fn copy_headers<H: Headers>(headers: Option<&H>, skip: &[&str]) -> OwnedHeaders {
+    let mut copied = OwnedHeaders::new();
+    if let Some(headers) = headers {
+        for header in headers.iter().filter(|header| !skip.contains(&header.key)) {
+            copied = copied.insert(Header {
+                key: header.key,
+                value: header.value,
+            });
+        }
+    }
+    copied
+}
+
+fn header_value<H: Headers>(headers: Option<&H>, key: &str) -> Option<String> {
+    headers?
+        .iter()
+        .find(|header| header.key == key)
+        .and_then(|header| header.value)
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        copy_headers, header_value, FailurePolicy, DLQ_HEADERS, ERROR_KIND_HEADER,
+        ORIGINAL_TOPIC_HEADER,
+    };
+    use rdkafka::message::{Header, Headers, OwnedHeaders};
+
+    #[test]
+    fn test_default_dead_letter_topic() {
+        assert_eq!(
+            FailurePolicy::dead_letter_for("book-created"),
+            FailurePolicy::DeadLetter("book-created.dlq".to_string())
+        );
+    }
+
+    #[test]
+    fn test_replay_strips_dead_letter_headers() {
+        let headers = OwnedHeaders::new()
+            .insert(Header {
+                key: "traceparent",
+                value: Some("00-abc"),
+            })
+            .insert(Header {
+                key: ORIGINAL_TOPIC_HEADER,
+                value: Some("book-created"),
+            })
+            .insert(Header {
+                key: ERROR_KIND_HEADER,
+                value: Some("decode"),
+            });
+        assert_eq!(
+            header_value(Some(&headers), ORIGINAL_TOPIC_HEADER),
+            Some("book-created".to_string())
+        );
+
+        let replayed = copy_headers(Some(&headers), &DLQ_HEADERS);
+        assert_eq!(replayed.count(), 1);
+        assert_eq!(
+            header_value(Some(&replayed), "traceparent"),
+            Some("00-abc".to_string())
+        );
+    }
+}
@@ -0,0 +1,429 @@
+//! Payload serialization formats. [`KafkaProducer`](crate::producer::KafkaProducer)
+//! and [`KafkaConsumer`](crate::consumer::KafkaConsumer) encode payloads with
+//! [`AvroSerde`] unless given another [`Serde`] through `with_serde`.
+
+use crate::dead_letter::{FailureKind, MessageFailure};
+use crate::producer::KafkaProducerError;
+use crate::util::supplied_schema;
+use apache_avro::{from_value, AvroSchema};
+use async_trait::async_trait;
+use schema_registry_converter::async_impl::avro::AvroDecoder;
+use schema_registry_converter::async_impl::easy_avro::EasyAvroEncoder;
+use schema_registry_converter::async_impl::easy_json::EasyJsonEncoder;
+use schema_registry_converter::async_impl::easy_proto_raw::EasyProtoRawEncoder;
+use schema_registry_converter::async_impl::json::{validate, JsonDecoder};
+use schema_registry_converter::async_impl::proto_raw::ProtoRawDecoder;
+use schema_registry_converter::async_impl::schema_registry::SrSettings;
+use schema_registry_converter::error::SRCError;
+use schema_registry_converter::schema_registry_common::{
+    SchemaType, SubjectNameStrategy, SuppliedSchema,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+use tracing::error;
+
+/// Encodes and decodes payloads of type `T` for records on a topic.
+#[async_trait]
+pub trait Serde<T>: Send + Sync {
+    /// Encodes `payload` as the value of a record on `topic`.
+    async fn serialize(&self, topic: &str, payload: T) -> Result<Vec<u8>, KafkaProducerError>;
+
+    /// Decodes a record value written by [`Serde::serialize`].
+    async fn deserialize(&self, bytes: Option<&[u8]>) -> Result<T, MessageFailure>;
+}
+
+fn decode_failure(reason: impl ToString) -> MessageFailure {
+    MessageFailure {
+        kind: FailureKind::Decode,
+        reason: reason.to_string(),
+    }
+}
+
+fn deserialize_failure(reason: impl ToString) -> MessageFailure {
+    MessageFailure {
+        kind: FailureKind::Deserialize,
+        reason: reason.to_string(),
+    }
+}
+
+/// Registry errors that may go away on their own are not the record's fault.
+/// Decoders cache failed lookups, so `remove_errors_from_cache` is called to
+/// let the next attempt ask the registry again.
+fn registry_failure(e: SRCError, remove_errors_from_cache: impl FnOnce()) -> MessageFailure {
+    if e.retriable {
+        remove_errors_from_cache();
+        MessageFailure {
+            kind: FailureKind::Unavailable,
+            reason: e.to_string(),
+        }
+    } else {
+        decode_failure(e)
+    }
+}
+
+/// Avro, with the writer schema registered under the topic's subject.
+#[derive(Clone)]
+pub struct AvroSerde {
+    encoder: Arc<EasyAvroEncoder>,
+    decoder: Arc<AvroDecoder<'static>>,
+}
+
+impl AvroSerde {
+    pub fn new(schema_registry_url: String) -> Self {
+        let sr_settings = SrSettings::new(schema_registry_url);
+        Self {
+            encoder: Arc::new(EasyAvroEncoder::new(sr_settings.clone())),
+            decoder: Arc::new(AvroDecoder::new(sr_settings)),
+        }
+    }
+
+    /// Encodes `payload` under the topic's key or value subject.
+    pub(crate) async fn encode<T: Serialize + AvroSchema>(
+        &self,
+        topic: &str,
+        is_key: bool,
+        payload: T,
+    ) -> Result<Vec<u8>, KafkaProducerError> {
+        let schema = T::get_schema();
+        // Resolve locally first so schema mismatches are not reported as registry failures.
+        apache_avro::to_value(&payload)?.resolve(&schema)?;
+        let strategy = SubjectNameStrategy::TopicNameStrategyWithSchema(
+            topic.to_owned(),
+            is_key,
+            supplied_schema(&schema)?,
+        );
+        self.encoder
+            .encode_struct(payload, &strategy)
+            .await
+            .map_err(|e| {
+                error!("Error getting payload: {}", e);
+                KafkaProducerError::SchemaRegistry(e)
+            })
+    }
+
+    pub(crate) async fn decode<T: DeserializeOwned>(
+        &self,
+        bytes: Option<&[u8]>,
+    ) -> Result<T, MessageFailure> {
+        let value = self
+            .decoder
+            .decode(bytes)
+            .await
+            .map_err(|e| registry_failure(e, || self.decoder.remove_errors_from_cache()))?
+            .value;
+        from_value::<T>(&value).map_err(deserialize_failure)
+    }
+}
+
+#[async_trait]
+impl<T> Serde<T> for AvroSerde
+where
+    T: Serialize + DeserializeOwned + AvroSchema + Send + 'static,
+{
+    async fn serialize(&self, topic: &str, payload: T) -> Result<Vec<u8>, KafkaProducerError> {
+        self.encode(topic, false, payload).await
+    }
+
+    async fn deserialize(&self, bytes: Option<&[u8]>) -> Result<T, MessageFailure> {
+        self.decode(bytes).await
+    }
+}
+
+struct JsonRegistry {
+    encoder: EasyJsonEncoder,
+    decoder: JsonDecoder<'static>,
+    schema: String,
+}
+
+/// JSON, either plain or validated against a JSON Schema registered under
+/// the topic's value subject.
+#[derive(Clone, Default)]
+pub struct JsonSerde {
+    registry: Option<Arc<JsonRegistry>>,
+}
+
+impl JsonSerde {
+    /// Plain JSON, without schema registry framing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// JSON framed with the id of `schema`, which payloads are validated
+    /// against when they are produced and consumed.
+    pub fn with_schema_registry(schema_registry_url: String, schema: serde_json::Value) -> Self {
+        let sr_settings = SrSettings::new(schema_registry_url);
+        Self {
+            registry: Some(Arc::new(JsonRegistry {
+                encoder: EasyJsonEncoder::new(sr_settings.clone()),
+                decoder: JsonDecoder::new(sr_settings),
+                schema: schema.to_string(),
+            })),
+        }
+    }
+}
+
+#[async_trait]
+impl<T> Serde<T> for JsonSerde
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    async fn serialize(&self, topic: &str, payload: T) -> Result<Vec<u8>, KafkaProducerError> {
+        let Some(registry) = &self.registry else {
+            return Ok(serde_json::to_vec(&payload)?);
+        };
+        let strategy = SubjectNameStrategy::TopicNameStrategyWithSchema(
+            topic.to_owned(),
+            false,
+            Box::new(SuppliedSchema {
+                name: None,
+                schema_type: SchemaType::Json,
+                schema: registry.schema.clone(),
+                references: vec![],
+            }),
+        );
+        Ok(registry
+            .encoder
+            .encode(&serde_json::to_value(&payload)?, strategy)
+            .await?)
+    }
+
+    async fn deserialize(&self, bytes: Option<&[u8]>) -> Result<T, MessageFailure> {
+        let Some(registry) = &self.registry else {
+            let bytes = bytes.ok_or_else(|| decode_failure("the record has no payload"))?;
+            return serde_json::from_slice(bytes).map_err(|e| {
+                if e.is_data() {
+                    deserialize_failure(e)
+                } else {
+                    decode_failure(e)
+                }
+            });
+        };
+        let decoded = registry
+            .decoder
+            .decode(bytes)
+            .await
+            .map_err(|e| registry_failure(e, || registry.decoder.remove_errors_from_cache()))?
+            .ok_or_else(|| decode_failure("the record has no payload"))?;
+        validate(decoded.schema, &decoded.value).map_err(deserialize_failure)?;
+        serde_json::from_value(decoded.value).map_err(deserialize_failure)
+    }
+}
+
+struct ProtobufRegistry {
+    encoder: EasyProtoRawEncoder,
+    decoder: ProtoRawDecoder<'static>,
+    schema: String,
+    full_name: String,
+}
+
+/// Protocol Buffers, either plain or framed with the id of a `.proto` schema
+/// registered under the topic's value subject.
+#[derive(Clone, Default)]
+pub struct ProtobufSerde {
+    registry: Option<Arc<ProtobufRegistry>>,
+}
+
+impl ProtobufSerde {
+    /// Plain Protobuf messages, without schema registry framing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Messages of type `full_name`, the package-qualified name of a message
+    /// defined in `schema`. Consumers reject records of any other message type.
+    pub fn with_schema_registry(
+        schema_registry_url: String,
+        schema: impl Into<String>,
+        full_name: impl Into<String>,
+    ) -> Self {
+        let sr_settings = SrSettings::new(schema_registry_url);
+        Self {
+            registry: Some(Arc::new(ProtobufRegistry {
+                encoder: EasyProtoRawEncoder::new(sr_settings.clone()),
+                decoder: ProtoRawDecoder::new(sr_settings),
+                schema: schema.into(),
+                full_name: full_name.into(),
+            })),
+        }
+    }
+}
+
+#[async_trait]
+impl<T> Serde<T> for ProtobufSerde
+where
+    T: prost::Message + Default + 'static,
+{
+    async fn serialize(&self, topic: &str, payload: T) -> Result<Vec<u8>, KafkaProducerError> {
+        let bytes = payload.encode_to_vec();
+        let Some(registry) = &self.registry else {
+            return Ok(bytes);
+        };
+        let strategy = SubjectNameStrategy::TopicNameStrategyWithSchema(
+            topic.to_owned(),
+            false,
+            Box::new(SuppliedSchema {
+                name: None,
+                schema_type: SchemaType::Protobuf,
+                schema: registry.schema.clone(),
+                references: vec![],
+            }),
+        );
+        Ok(registry
+            .encoder
+            .encode(&bytes, &registry.full_name, strategy)
+            .await?)
+    }
+
+    async fn deserialize(&self, bytes: Option<&[u8]>) -> Result<T, MessageFailure> {
+        let Some(registry) = &self.registry else {
+            let bytes = bytes.ok_or_else(|| decode_failure("the record has no payload"))?;
+            return T::decode(bytes).map_err(deserialize_failure);
+        };
+        let decoded = registry
+            .decoder
+            .decode(bytes)
+            .await
+            .map_err(|e| registry_failure(e, || registry.decoder.remove_errors_from_cache()))?
+            .ok_or_else(|| decode_failure("the record has no payload"))?;
+        if *decoded.full_name != registry.full_name {
+            return Err(deserialize_failure(format!(
+                "expected a {} message, got {}",
+                registry.full_name, decoded.full_name
+            )));
+        }
+        T::decode(decoded.bytes.as_slice()).map_err(deserialize_failure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AvroSerde, JsonSerde, ProtobufSerde, Serde};
+    use crate::dead_letter::FailureKind;
+    use crate::mock_schema_registry::MockSchemaRegistry;
+    use crate::producer::KafkaProducerError;
+    use apache_avro::AvroSchema;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, AvroSchema)]
+    struct Custom {
+        value: String,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+    struct Counter {
+        count: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Reading {
+        #[prost(string, tag = "1")]
+        sensor: String,
+        #[prost(int64, tag = "2")]
+        value: i64,
+    }
+
+    const READINGS_PROTO: &str = r#"
+        syntax = "proto3";
+        package sensors;
+
+        message Reading {
+          string sensor = 1;
+          int64 value = 2;
+        }
+    "#;
+
+    fn custom() -> Custom {
+        Custom {
+            value: "payload".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_avro_and_json_serdes() {
+        let registry = MockSchemaRegistry::start().await;
+        let avro = AvroSerde::new(registry.url().to_owned());
+        let encoded = avro.serialize("custom", custom()).await.unwrap();
+        assert_eq!(
+            Serde::<Custom>::deserialize(&avro, Some(&encoded))
+                .await
+                .unwrap(),
+            custom()
+        );
+
+        let plain = JsonSerde::new();
+        let encoded = plain.serialize("custom", custom()).await.unwrap();
+        assert_eq!(encoded, br#"{"value":"payload"}"#);
+        assert_eq!(
+            Serde::<Custom>::deserialize(&plain, Some(&encoded))
+                .await
+                .unwrap(),
+            custom()
+        );
+        let mismatched = Serde::<Counter>::deserialize(&plain, Some(&encoded)).await;
+        assert!(matches!(mismatched, Err(failure) if failure.kind == FailureKind::Deserialize));
+
+        let schema = json!({
+            "type": "object",
+            "properties": { "value": { "type": "string" } },
+            "required": ["value"]
+        });
+        let validated = JsonSerde::with_schema_registry(registry.url().to_owned(), schema);
+        let encoded = validated.serialize("json", custom()).await.unwrap();
+        assert_eq!(encoded[0], 0);
+        assert_eq!(
+            Serde::<Custom>::deserialize(&validated, Some(&encoded))
+                .await
+                .unwrap(),
+            custom()
+        );
+        assert!(matches!(
+            validated.serialize("json", Counter { count: 1 }).await,
+            Err(KafkaProducerError::SchemaRegistry(_))
+        ));
+        assert_eq!(
+            registry.subjects(),
+            vec!["custom-value".to_string(), "json-value".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_protobuf_serde() {
+        let reading = Reading {
+            sensor: "thermometer".to_string(),
+            value: 21,
+        };
+        let plain = ProtobufSerde::new();
+        let encoded = plain.serialize("readings", reading.clone()).await.unwrap();
+        assert_eq!(
+            Serde::<Reading>::deserialize(&plain, Some(&encoded))
+                .await
+                .unwrap(),
+            reading
+        );
+
+        let registry = MockSchemaRegistry::start().await;
+        let serde = ProtobufSerde::with_schema_registry(
+            registry.url().to_owned(),
+            READINGS_PROTO,
+            "sensors.Reading",
+        );
+        let encoded = serde.serialize("readings", reading.clone()).await.unwrap();
+        assert_eq!(registry.subjects(), vec!["readings-value".to_string()]);
+        assert_eq!(
+            Serde::<Reading>::deserialize(&serde, Some(&encoded))
+                .await
+                .unwrap(),
+            reading
+        );
+
+        // Records of another message type are rejected rather than misread.
+        let other = ProtobufSerde::with_schema_registry(
+            registry.url().to_owned(),
+            READINGS_PROTO,
+            "sensors.Alert",
+        );
+        let mismatched = Serde::<Reading>::deserialize(&other, Some(&encoded)).await;
+        assert!(matches!(mismatched, Err(failure) if failure.kind == FailureKind::Deserialize));
+    }
+}
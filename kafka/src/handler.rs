@@ -0,0 +1,206 @@
+use crate::dead_letter::{FailureKind, MessageFailure};
+use async_trait::async_trait;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc::{error::SendError, Sender, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// Processes one decoded message. `KafkaConsumer` commits the message's
+/// offset only once this returns `Ok`.
+#[async_trait]
+pub trait MessageHandler<T: Send + 'static>: Send + Sync {
+    type Error: Display + Send;
+
+    async fn handle(&self, message: T) -> Result<(), Self::Error>;
+
+    /// Whether the handler has no room for another message right now. While
+    /// saturated, the consumer pauses fetching on its assigned partitions.
+    fn is_saturated(&self) -> bool {
+        false
+    }
+
+    /// Completes once the handler can accept another message.
+    async fn ready(&self) {}
+}
+
+/// Hands messages to a bounded channel, applying backpressure to the consumer
+/// when the receiver falls behind.
+#[async_trait]
+impl<T: Send + 'static> MessageHandler<T> for Sender<T> {
+    type Error = SendError<T>;
+
+    async fn handle(&self, message: T) -> Result<(), Self::Error> {
+        self.send(message).await
+    }
+
+    fn is_saturated(&self) -> bool {
+        self.capacity() == 0
+    }
+
+    async fn ready(&self) {
+        // A closed channel is reported by the next `handle` call.
+        let _ = self.reserve().await;
+    }
+}
+
+/// Hands messages to a channel; the offset is committed as soon as the
+/// receiver has been given the message, not when it has processed it.
+#[async_trait]
+impl<T: Send + 'static> MessageHandler<T> for UnboundedSender<T> {
+    type Error = SendError<T>;
+
+    async fn handle(&self, message: T) -> Result<(), Self::Error> {
+        self.send(message)
+    }
+}
+
+/// Hands the payload of keyed messages to a handler that has no use for the key.
+pub(crate) struct IgnoreKey<'h, H>(pub(crate) &'h H);
+
+#[async_trait]
+impl<'h, K, T, H> MessageHandler<(K, T)> for IgnoreKey<'h, H>
+where
+    K: Send + 'static,
+    T: Send + 'static,
+    H: MessageHandler<T>,
+{
+    type Error = H::Error;
+
+    async fn handle(&self, (_, message): (K, T)) -> Result<(), Self::Error> {
+        self.0.handle(message).await
+    }
+
+    fn is_saturated(&self) -> bool {
+        self.0.is_saturated()
+    }
+
+    async fn ready(&self) {
+        self.0.ready().await
+    }
+}
+
+/// How often a failing handler is retried before the message is treated as
+/// failed and given to the consumer's `FailurePolicy`. An unavailable schema
+/// registry is not the message's fault, so lookups are retried with the same
+/// capped backoff until the registry is back or the consumer shuts down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Backoff before retry number `attempt`, starting at 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+
+    /// Runs `decode` again with backoff while it fails with a retriable
+    /// failure, however often, until it succeeds or `shutdown` is cancelled.
+    pub(crate) async fn decode<T, F, R>(
+        &self,
+        shutdown: &CancellationToken,
+        decode: F,
+    ) -> Result<T, MessageFailure>
+    where
+        F: Fn() -> R,
+        R: Future<Output = Result<T, MessageFailure>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match decode().await {
+                Err(failure) if failure.is_retriable() && !shutdown.is_cancelled() => {
+                    attempt += 1;
+                    let backoff = self.backoff(attempt);
+                    warn!(
+                        "Unable to {} message (attempt {}), retrying in {:?}: {}",
+                        failure.kind, attempt, backoff, failure.reason
+                    );
+                    tokio::select! {
+                        _ = shutdown.cancelled() => return Err(failure),
+                        _ = tokio::time::sleep(backoff) => {}
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Hands `payload` to `handler`, retrying with backoff until it succeeds
+    /// or the retries run out.
+    pub(crate) async fn handle<T, H>(&self, handler: &H, payload: T) -> Result<(), MessageFailure>
+    where
+        T: Clone + Send + 'static,
+        H: MessageHandler<T>,
+    {
+        let mut attempt = 0;
+        loop {
+            match handler.handle(payload.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.max_retries => {
+                    attempt += 1;
+                    let backoff = self.backoff(attempt);
+                    warn!(
+                        "Handler failed (attempt {}), retrying in {:?}: {}",
+                        attempt, backoff, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                }
+                Err(e) => {
+                    return Err(MessageFailure {
+                        kind: FailureKind::Handler,
+                        reason: e.to_string(),
+                    })
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MessageHandler, RetryPolicy};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_bounded_sender_saturation() {
+        let (sender, mut receiver) = mpsc::channel::<u32>(1);
+        assert!(!MessageHandler::is_saturated(&sender));
+        sender.handle(1).await.unwrap();
+        assert!(MessageHandler::is_saturated(&sender));
+
+        assert_eq!(receiver.recv().await, Some(1));
+        MessageHandler::ready(&sender).await;
+        assert!(!MessageHandler::is_saturated(&sender));
+    }
+
+    #[test]
+    fn test_backoff_is_exponential_and_capped() {
+        let retry_policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        };
+        assert_eq!(retry_policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(retry_policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(retry_policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(retry_policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(retry_policy.backoff(40), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,221 @@
+//! Typed message keys, encoded per topic according to a [`KeyFormat`].
+
+use apache_avro::schema::{derive::AvroSchemaComponent, Name, Namespace};
+use apache_avro::{from_avro_datum, from_value, to_avro_datum, types::Value, AvroSchema, Schema};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use thiserror::Error;
+
+/// How a topic's keys are encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// UTF-8 text. Integer keys are written as their decimal digits.
+    #[default]
+    String,
+    /// Big-endian integers, 4 bytes for `int` keys and 8 for `long` keys, as
+    /// written by Kafka's `IntegerSerializer` and `LongSerializer`.
+    Integer,
+    /// Avro, with the schema registered under the topic's `<topic>-key` subject.
+    Avro,
+}
+
+impl fmt::Display for KeyFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyFormat::String => "string",
+            KeyFormat::Integer => "integer",
+            KeyFormat::Avro => "avro",
+        })
+    }
+}
+
+/// A type records can be keyed by.
+pub trait MessageKey:
+    Serialize + for<'a> Deserialize<'a> + AvroSchema + Clone + Debug + Send + Sync + 'static
+{
+}
+
+impl<K> MessageKey for K where
+    K: Serialize + for<'a> Deserialize<'a> + AvroSchema + Clone + Debug + Send + Sync + 'static
+{
+}
+
+/// Key of records produced without one. Consuming with it ignores whatever
+/// key a record has.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Unkeyed;
+
+impl AvroSchemaComponent for Unkeyed {
+    fn get_schema_in_ctxt(_: &mut HashMap<Name, Schema>, _: &Namespace) -> Schema {
+        Schema::Null
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("Avro error: {0}")]
+    Avro(#[from] apache_avro::Error),
+
+    #[error("{format} keys cannot hold {schema} values")]
+    Unsupported { format: KeyFormat, schema: String },
+
+    #[error("Key is not a valid {format} key: {reason}")]
+    Malformed { format: KeyFormat, reason: String },
+}
+
+/// Whether `K` is [`Unkeyed`], so no key is written or read.
+pub(crate) fn is_unkeyed<K: AvroSchema>() -> bool {
+    K::get_schema() == Schema::Null
+}
+
+/// Encodes `key` in `format`, or returns `None` for [`Unkeyed`]. Avro keys
+/// are encoded as a bare datum; [`KafkaProducer`](crate::producer::KafkaProducer)
+/// frames them through the schema registry instead.
+pub(crate) fn encode_key<K: MessageKey>(
+    format: KeyFormat,
+    key: &K,
+) -> Result<Option<Vec<u8>>, KeyError> {
+    let schema = K::get_schema();
+    if schema == Schema::Null {
+        return Ok(None);
+    }
+    let value = apache_avro::to_value(key)?.resolve(&schema)?;
+    let bytes = match (format, value) {
+        (KeyFormat::String, Value::String(text)) => text.into_bytes(),
+        (KeyFormat::String, Value::Int(number)) => number.to_string().into_bytes(),
+        (KeyFormat::String, Value::Long(number)) => number.to_string().into_bytes(),
+        (KeyFormat::Integer, Value::Int(number)) => number.to_be_bytes().to_vec(),
+        (KeyFormat::Integer, Value::Long(number)) => number.to_be_bytes().to_vec(),
+        (KeyFormat::Avro, value) => to_avro_datum(&schema, value)?,
+        (format, _) => {
+            return Err(KeyError::Unsupported {
+                format,
+                schema: schema.canonical_form(),
+            })
+        }
+    };
+    Ok(Some(bytes))
+}
+
+/// Decodes a key written by [`encode_key`]. [`Unkeyed`] accepts any key.
+pub(crate) fn decode_key<K: MessageKey>(
+    format: KeyFormat,
+    bytes: Option<&[u8]>,
+) -> Result<K, KeyError> {
+    let schema = K::get_schema();
+    if schema == Schema::Null {
+        return Ok(from_value(&Value::Null)?);
+    }
+    let bytes = bytes.ok_or_else(|| KeyError::Malformed {
+        format,
+        reason: "the record has no key".to_owned(),
+    })?;
+    let malformed = |reason: String| KeyError::Malformed { format, reason };
+    let value = match (format, &schema) {
+        (KeyFormat::String, Schema::String) => {
+            Value::String(String::from_utf8(bytes.to_vec()).map_err(|e| malformed(e.to_string()))?)
+        }
+        (KeyFormat::String, Schema::Int | Schema::Long) => {
+            let text = std::str::from_utf8(bytes).map_err(|e| malformed(e.to_string()))?;
+            let number = text.parse::<i64>().map_err(|e| malformed(e.to_string()))?;
+            integer_value(&schema, number).map_err(malformed)?
+        }
+        (KeyFormat::Integer, Schema::Int | Schema::Long) => {
+            let number = match bytes.len() {
+                4 => i32::from_be_bytes(bytes.try_into().expect("length checked")) as i64,
+                8 => i64::from_be_bytes(bytes.try_into().expect("length checked")),
+                length => return Err(malformed(format!("expected 4 or 8 bytes, got {}", length))),
+            };
+            integer_value(&schema, number).map_err(malformed)?
+        }
+        (KeyFormat::Avro, _) => from_avro_datum(&schema, &mut &bytes[..], None)?,
+        (format, _) => {
+            return Err(KeyError::Unsupported {
+                format,
+                schema: schema.canonical_form(),
+            })
+        }
+    };
+    Ok(from_value(&value)?)
+}
+
+fn integer_value(schema: &Schema, number: i64) -> Result<Value, String> {
+    match schema {
+        Schema::Int => i32::try_from(number)
+            .map(Value::Int)
+            .map_err(|_| format!("{} does not fit an int", number)),
+        _ => Ok(Value::Long(number)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_key, encode_key, KeyError, KeyFormat, Unkeyed};
+    use apache_avro::AvroSchema;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, AvroSchema)]
+    struct CompositeKey {
+        tenant: String,
+        id: i64,
+    }
+
+    #[test]
+    fn test_key_roundtrips() {
+        let encoded = encode_key(KeyFormat::String, &42).unwrap();
+        assert_eq!(encoded.as_deref(), Some("42".as_bytes()));
+        assert_eq!(
+            decode_key::<i32>(KeyFormat::String, encoded.as_deref()).unwrap(),
+            42
+        );
+
+        let encoded = encode_key(KeyFormat::Integer, &42i64).unwrap();
+        assert_eq!(encoded.as_deref(), Some(&42i64.to_be_bytes()[..]));
+        assert_eq!(
+            decode_key::<i64>(KeyFormat::Integer, encoded.as_deref()).unwrap(),
+            42
+        );
+        assert_eq!(
+            decode_key::<i64>(KeyFormat::Integer, Some(&7i32.to_be_bytes())).unwrap(),
+            7
+        );
+
+        let key = CompositeKey {
+            tenant: "books".to_string(),
+            id: 7,
+        };
+        let encoded = encode_key(KeyFormat::Avro, &key).unwrap();
+        assert_eq!(
+            decode_key::<CompositeKey>(KeyFormat::Avro, encoded.as_deref()).unwrap(),
+            key
+        );
+    }
+
+    #[test]
+    fn test_unkeyed_and_invalid_keys() {
+        assert_eq!(encode_key(KeyFormat::Avro, &Unkeyed).unwrap(), None);
+        assert_eq!(
+            decode_key::<Unkeyed>(KeyFormat::String, Some(b"anything")).unwrap(),
+            Unkeyed
+        );
+        assert!(matches!(
+            decode_key::<i32>(KeyFormat::String, Some(b"not a number")),
+            Err(KeyError::Malformed { .. })
+        ));
+        assert!(matches!(
+            decode_key::<i32>(KeyFormat::String, None),
+            Err(KeyError::Malformed { .. })
+        ));
+        assert!(matches!(
+            encode_key(
+                KeyFormat::Integer,
+                &CompositeKey {
+                    tenant: "books".to_string(),
+                    id: 7,
+                }
+            ),
+            Err(KeyError::Unsupported { .. })
+        ));
+    }
+}
@@ -1,4 +1,13 @@
 pub mod consumer;
+pub mod dead_letter;
+pub mod format;
+pub mod handler;
+pub mod key;
+#[cfg(any(test, feature = "test-util"))]
+pub mod memory;
+pub mod metrics;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock_schema_registry;
 pub mod producer;
 pub mod util;
 
@@ -8,8 +17,19 @@ mod tests {
     use apache_avro::AvroSchema;
     use serde::{Deserialize, Serialize};
     use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
 
-    use crate::{consumer::KafkaConsumer, producer::KafkaProducer};
+    use crate::{
+        consumer::{Consumer, KafkaConsumer},
+        format::AvroSerde,
+        memory::InMemoryBroker,
+        mock_schema_registry::MockSchemaRegistry,
+        producer::{
+            Compression, KafkaProducer, KafkaProducerConfigBuilder, KafkaProducerError, Producer,
+        },
+    };
+    use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_produce() {
@@ -17,32 +37,22 @@ mod tests {
         let key = "test-key";
         let payload = "test-payload";
 
-        let kafka_producer = KafkaProducer::new(
-            "localhost:9092".to_string(),
-            "http://localhost:8081".to_string(),
-            topic.to_string(),
-        );
-        let kakfa_consumer = KafkaConsumer::new(
-            "localhost:9092".to_string(),
-            "http://localhost:8081".to_string(),
-            "string-consumer".to_string(),
-            topic.to_string(),
-        );
+        let broker = InMemoryBroker::new();
+        let producer = broker.producer(topic);
+        let consumer = broker.consumer("string-consumer", topic);
 
-        let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
-        let produce_result = kafka_producer
-            .produce(key.to_string(), payload.to_string())
-            .await;
-        assert!(produce_result);
-        let handle = tokio::spawn(async move {
-            kakfa_consumer.consume(sender.clone()).await;
+        let (sender, mut receiver) = mpsc::channel::<String>(16);
+        let produce_result = producer.produce(key.to_string(), payload.to_string()).await;
+        assert!(produce_result.is_ok());
+        let shutdown = CancellationToken::new();
+        let handle = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { consumer.consume(sender, shutdown).await }
         });
 
-        while let Some(message) = receiver.recv().await {
-            assert_eq!(payload.to_string(), message);
-            break;
-        }
-        handle.abort();
+        assert_eq!(receiver.recv().await, Some(payload.to_string()));
+        shutdown.cancel();
+        handle.await.unwrap().unwrap();
     }
 
     #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, AvroSchema)]
@@ -57,6 +67,31 @@ mod tests {
         let payload = Custom {
             value: "test-payload".to_string(),
         };
+        let registry = MockSchemaRegistry::start().await;
+        let serde = AvroSerde::new(registry.url().to_owned());
+        let broker = InMemoryBroker::new();
+        let producer = broker.producer(topic).with_serde(serde.clone());
+        let consumer = broker.consumer("custom-consumer", topic).with_serde(serde);
+
+        let produce_result = producer.produce(key.to_string(), payload.clone()).await;
+        assert!(produce_result.is_ok());
+        assert_eq!(registry.subjects(), vec![format!("{}-value", topic)]);
+        let (sender, mut receiver) = mpsc::channel::<Custom>(16);
+        let shutdown = CancellationToken::new();
+        let handle = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { consumer.consume(sender, shutdown).await }
+        });
+
+        assert_eq!(receiver.recv().await, Some(payload));
+        shutdown.cancel();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Kafka broker on localhost:9092 and a schema registry on localhost:8081"]
+    async fn test_consume_partitioned() {
+        let topic = "partitioned-topic";
         let kafka_producer = KafkaProducer::new(
             "localhost:9092".to_string(),
             "http://localhost:8081".to_string(),
@@ -65,23 +100,86 @@ mod tests {
         let kakfa_consumer = KafkaConsumer::new(
             "localhost:9092".to_string(),
             "http://localhost:8081".to_string(),
-            "custom-consumer".to_string(),
+            "partitioned-consumer".to_string(),
             topic.to_string(),
-        );
+        )
+        .with_max_concurrency(4);
 
-        let produce_result = kafka_producer
-            .produce(key.to_string(), payload.clone())
-            .await;
-        assert!(produce_result);
-        let (sender, mut receiver) = mpsc::unbounded_channel::<Custom>();
+        for index in 0..3 {
+            let produce_result = kafka_producer
+                .produce(format!("key-{}", index), format!("payload-{}", index))
+                .await;
+            assert!(produce_result.is_ok());
+        }
+        let (sender, mut receiver) = mpsc::channel::<String>(16);
         let handle = tokio::spawn(async move {
-            kakfa_consumer.consume(sender.clone()).await;
+            kakfa_consumer
+                .consume_partitioned(&sender, CancellationToken::new())
+                .await
+                .unwrap();
         });
 
-        while let Some(message) = receiver.recv().await {
-            assert_eq!(payload, message);
-            break;
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            received.push(receiver.recv().await.unwrap());
         }
-        handle.abort()
+        received.sort();
+        assert_eq!(received, vec!["payload-0", "payload-1", "payload-2"]);
+        handle.abort();
+    }
+
+    #[test]
+    fn test_producer_error_classification() {
+        let timeout: KafkaProducerError =
+            KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut).into();
+        assert!(matches!(timeout, KafkaProducerError::DeliveryTimeout));
+        assert!(timeout.is_retriable());
+
+        let queue_full: KafkaProducerError =
+            KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull).into();
+        assert!(matches!(queue_full, KafkaProducerError::QueueFull));
+
+        let too_large: KafkaProducerError =
+            KafkaError::MessageProduction(RDKafkaErrorCode::MessageSizeTooLarge).into();
+        assert!(matches!(too_large, KafkaProducerError::BrokerRejected(_)));
+        assert!(!too_large.is_retriable());
+    }
+
+    #[test]
+    fn test_producer_client_config() {
+        let mut builder = KafkaProducerConfigBuilder::default();
+        builder
+            .bootstrap_servers("localhost:9092")
+            .schema_registry_url("http://localhost:8081")
+            .topic("topic");
+        let plain = builder.build().unwrap().client_config();
+        assert_eq!(plain.get("enable.idempotence"), None);
+        assert_eq!(plain.get("compression.type"), Some("none"));
+        assert_eq!(plain.get("batch.size"), None);
+
+        let idempotent = builder
+            .idempotent(true)
+            .compression(Compression::Zstd)
+            .linger(Duration::from_millis(20))
+            .batch_size(Some(65_536))
+            .build()
+            .unwrap()
+            .client_config();
+        assert_eq!(idempotent.get("enable.idempotence"), Some("true"));
+        assert_eq!(idempotent.get("acks"), Some("all"));
+        assert_eq!(idempotent.get("compression.type"), Some("zstd"));
+        assert_eq!(idempotent.get("linger.ms"), Some("20"));
+        assert_eq!(idempotent.get("batch.size"), Some("65536"));
+        assert_eq!(idempotent.get("transactional.id"), None);
+
+        let transactional = builder
+            .idempotent(false)
+            .transactional_id(Some("books-api-1".to_string()))
+            .build()
+            .unwrap()
+            .client_config();
+        assert_eq!(transactional.get("enable.idempotence"), Some("true"));
+        assert_eq!(transactional.get("transactional.id"), Some("books-api-1"));
+        assert_eq!(transactional.get("transaction.timeout.ms"), Some("60000"));
     }
 }
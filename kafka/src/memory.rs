@@ -0,0 +1,898 @@
+//! An in-process stand-in for Kafka and the schema registry, so producers and
+//! consumers can be tested without containers.
+//!
+//! By default payloads are stored Avro-encoded together with their writer
+//! schema by [`InMemoryAvroSerde`], so schema mismatches still surface as
+//! failures. Producers and consumers can be given another [`Serde`] with
+//! `with_serde`; registry-backed ones need a reachable registry, such as a
+//! [`MockSchemaRegistry`](crate::mock_schema_registry::MockSchemaRegistry).
+//! Each consumer group is served by a single consumer that owns every partition.
+
+use crate::consumer::{Consumer, KafkaConsumerError};
+use crate::dead_letter::{
+    FailureKind, FailurePolicy, MessageFailure, ERROR_KIND_HEADER, ERROR_MESSAGE_HEADER,
+    ORIGINAL_OFFSET_HEADER, ORIGINAL_PARTITION_HEADER, ORIGINAL_TOPIC_HEADER,
+};
+use crate::format::Serde;
+use crate::handler::{IgnoreKey, MessageHandler, RetryPolicy};
+use crate::key::{decode_key, encode_key, KeyFormat, MessageKey, Unkeyed};
+use crate::producer::{DeliveryReport, KafkaProducerError, Producer};
+use apache_avro::{from_avro_datum, from_value, to_avro_datum, AvroSchema, Schema};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+const DEFAULT_PARTITIONS: i32 = 3;
+
+/// A record as stored by [`InMemoryBroker`].
+#[derive(Debug, Clone)]
+pub struct InMemoryRecord {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<Vec<u8>>,
+    pub headers: Vec<(String, String)>,
+    pub payload: Vec<u8>,
+    sequence: u64,
+}
+
+impl InMemoryRecord {
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Decodes the key as written by a producer using `key_format`.
+    pub fn decode_key<K: MessageKey>(&self, key_format: KeyFormat) -> Result<K, MessageFailure> {
+        decode_key(key_format, self.key.as_deref()).map_err(|e| MessageFailure {
+            kind: FailureKind::Decode,
+            reason: e.to_string(),
+        })
+    }
+
+    /// Decodes a payload written with [`InMemoryAvroSerde`], using the schema
+    /// it was written with.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, MessageFailure> {
+        InMemoryAvroSerde::decode(&self.payload)
+    }
+
+    /// Decodes a payload written with `serde`.
+    pub async fn decode_with<T, S: Serde<T>>(&self, serde: &S) -> Result<T, MessageFailure> {
+        serde.deserialize(Some(&self.payload)).await
+    }
+}
+
+/// Avro without a schema registry: each payload is the writer schema's JSON,
+/// prefixed with its length as a big-endian `u32`, followed by the datum.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InMemoryAvroSerde;
+
+impl InMemoryAvroSerde {
+    fn encode<T: Serialize + AvroSchema>(payload: &T) -> Result<Vec<u8>, KafkaProducerError> {
+        let schema = T::get_schema();
+        let value = apache_avro::to_value(payload)?.resolve(&schema)?;
+        let datum = to_avro_datum(&schema, value)?;
+        let schema = serde_json::to_vec(&schema)?;
+        let mut bytes = Vec::with_capacity(4 + schema.len() + datum.len());
+        bytes.extend_from_slice(&(schema.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&schema);
+        bytes.extend_from_slice(&datum);
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, MessageFailure> {
+        let decode_failure = |reason: String| MessageFailure {
+            kind: FailureKind::Decode,
+            reason,
+        };
+        let (length, rest) = bytes
+            .split_first_chunk::<4>()
+            .ok_or_else(|| decode_failure("payload is too short".to_owned()))?;
+        let length = u32::from_be_bytes(*length) as usize;
+        if rest.len() < length {
+            return Err(decode_failure("payload is too short".to_owned()));
+        }
+        let (schema, mut datum) = rest.split_at(length);
+        let schema = std::str::from_utf8(schema)
+            .map_err(|e| e.to_string())
+            .and_then(|schema| Schema::parse_str(schema).map_err(|e| e.to_string()))
+            .map_err(decode_failure)?;
+        let value = from_avro_datum(&schema, &mut datum, None)
+            .map_err(|e| decode_failure(e.to_string()))?;
+        from_value::<T>(&value).map_err(|e| MessageFailure {
+            kind: FailureKind::Deserialize,
+            reason: e.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl<T> Serde<T> for InMemoryAvroSerde
+where
+    T: Serialize + DeserializeOwned + AvroSchema + Send + 'static,
+{
+    async fn serialize(&self, _topic: &str, payload: T) -> Result<Vec<u8>, KafkaProducerError> {
+        Self::encode(&payload)
+    }
+
+    async fn deserialize(&self, bytes: Option<&[u8]>) -> Result<T, MessageFailure> {
+        let bytes = bytes.ok_or_else(|| MessageFailure {
+            kind: FailureKind::Decode,
+            reason: "the record has no payload".to_owned(),
+        })?;
+        Self::decode(bytes)
+    }
+}
+
+#[derive(Default)]
+struct BrokerState {
+    topics: HashMap<String, Vec<Vec<InMemoryRecord>>>,
+    /// Next offset to read, keyed by group, topic and partition.
+    committed_offsets: HashMap<(String, String, i32), i64>,
+    next_sequence: u64,
+}
+
+struct BrokerInner {
+    state: Mutex<BrokerState>,
+    appended: watch::Sender<u64>,
+    default_partitions: i32,
+}
+
+/// Topics, partitions and committed consumer-group offsets held in memory.
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct InMemoryBroker {
+    inner: Arc<BrokerInner>,
+}
+
+impl Default for InMemoryBroker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryBroker {
+    pub fn new() -> Self {
+        Self::with_default_partitions(DEFAULT_PARTITIONS)
+    }
+
+    /// Topics created implicitly, by producing to them, get `partitions` partitions.
+    pub fn with_default_partitions(partitions: i32) -> Self {
+        let (appended, _) = watch::channel(0);
+        Self {
+            inner: Arc::new(BrokerInner {
+                state: Mutex::new(BrokerState::default()),
+                appended,
+                default_partitions: partitions.max(1),
+            }),
+        }
+    }
+
+    /// Creates `topic` unless it already exists.
+    pub fn create_topic(&self, topic: &str, partitions: i32) {
+        self.state()
+            .topics
+            .entry(topic.to_owned())
+            .or_insert_with(|| vec![Vec::new(); partitions.max(1) as usize]);
+    }
+
+    pub fn producer(&self, topic: impl Into<String>) -> InMemoryProducer {
+        InMemoryProducer {
+            broker: self.clone(),
+            topic: topic.into(),
+            key_format: KeyFormat::default(),
+            serde: InMemoryAvroSerde,
+        }
+    }
+
+    pub fn consumer(
+        &self,
+        group_id: impl Into<String>,
+        topic: impl Into<String>,
+    ) -> InMemoryConsumer {
+        let topic = topic.into();
+        InMemoryConsumer {
+            broker: self.clone(),
+            group_id: group_id.into(),
+            failure_policy: FailurePolicy::dead_letter_for(&topic),
+            retry_policy: RetryPolicy::default(),
+            key_format: KeyFormat::default(),
+            serde: InMemoryAvroSerde,
+            topic,
+        }
+    }
+
+    /// Every record on `topic`, partition by partition.
+    pub fn records(&self, topic: &str) -> Vec<InMemoryRecord> {
+        self.state()
+            .topics
+            .get(topic)
+            .map(|partitions| partitions.iter().flatten().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The next offset `group_id` will read from the partition, if it has committed one.
+    pub fn committed_offset(&self, group_id: &str, topic: &str, partition: i32) -> Option<i64> {
+        self.state()
+            .committed_offsets
+            .get(&(group_id.to_owned(), topic.to_owned(), partition))
+            .copied()
+    }
+
+    fn state(&self) -> MutexGuard<'_, BrokerState> {
+        self.inner
+            .state
+            .lock()
+            .expect("in-memory broker lock poisoned")
+    }
+
+    fn append(
+        &self,
+        topic: &str,
+        key: Option<Vec<u8>>,
+        headers: Vec<(String, String)>,
+        payload: Vec<u8>,
+    ) -> DeliveryReport {
+        let mut state = self.state();
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+        let default_partitions = self.inner.default_partitions as usize;
+        let partitions = state
+            .topics
+            .entry(topic.to_owned())
+            .or_insert_with(|| vec![Vec::new(); default_partitions]);
+        let partition = match &key {
+            Some(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                (hasher.finish() % partitions.len() as u64) as i32
+            }
+            None => (sequence % partitions.len() as u64) as i32,
+        };
+        let records = &mut partitions[partition as usize];
+        let offset = records.len() as i64;
+        records.push(InMemoryRecord {
+            topic: topic.to_owned(),
+            partition,
+            offset,
+            key,
+            headers,
+            payload,
+            sequence,
+        });
+        drop(state);
+        self.inner.appended.send_replace(sequence);
+        DeliveryReport { partition, offset }
+    }
+
+    /// The oldest record on `topic` past `group_id`'s committed offsets.
+    fn next_uncommitted(&self, group_id: &str, topic: &str) -> Option<InMemoryRecord> {
+        let state = self.state();
+        state
+            .topics
+            .get(topic)?
+            .iter()
+            .enumerate()
+            .filter_map(|(partition, records)| {
+                let offset = state
+                    .committed_offsets
+                    .get(&(group_id.to_owned(), topic.to_owned(), partition as i32))
+                    .copied()
+                    .unwrap_or(0);
+                records.get(offset as usize)
+            })
+            .min_by_key(|record| record.sequence)
+            .cloned()
+    }
+
+    fn commit(&self, group_id: &str, record: &InMemoryRecord) {
+        self.state().committed_offsets.insert(
+            (group_id.to_owned(), record.topic.clone(), record.partition),
+            record.offset + 1,
+        );
+    }
+}
+
+/// A [`Producer`] that appends to an [`InMemoryBroker`].
+#[derive(Clone)]
+pub struct InMemoryProducer<S = InMemoryAvroSerde> {
+    broker: InMemoryBroker,
+    topic: String,
+    key_format: KeyFormat,
+    serde: S,
+}
+
+impl<S> InMemoryProducer<S> {
+    /// Encodes payloads with `serde` instead.
+    pub fn with_serde<S2>(self, serde: S2) -> InMemoryProducer<S2> {
+        InMemoryProducer {
+            broker: self.broker,
+            topic: self.topic,
+            key_format: self.key_format,
+            serde,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Clone + Send + Sync + 'static> Producer for InMemoryProducer<S> {
+    type Serde = S;
+
+    async fn produce_with_headers<K: MessageKey, T: Send + 'static>(
+        &self,
+        key: K,
+        payload: T,
+        headers: &[(&str, &str)],
+    ) -> Result<DeliveryReport, KafkaProducerError>
+    where
+        S: Serde<T>,
+    {
+        let payload = self.serde.serialize(&self.topic, payload).await?;
+        let key = encode_key(self.key_format, &key)?;
+        let headers = headers
+            .iter()
+            .map(|(key, value)| ((*key).to_owned(), (*value).to_owned()))
+            .collect();
+        Ok(self.broker.append(&self.topic, key, headers, payload))
+    }
+
+    fn for_topic(&self, topic: impl Into<String>) -> Self {
+        Self {
+            topic: topic.into(),
+            ..self.clone()
+        }
+    }
+
+    fn with_key_format(mut self, key_format: KeyFormat) -> Self {
+        self.key_format = key_format;
+        self
+    }
+
+    fn flush(&self, _timeout: Duration) -> Result<(), KafkaProducerError> {
+        Ok(())
+    }
+}
+
+/// A [`Consumer`] reading one topic of an [`InMemoryBroker`] for a consumer group.
+pub struct InMemoryConsumer<S = InMemoryAvroSerde> {
+    broker: InMemoryBroker,
+    group_id: String,
+    topic: String,
+    failure_policy: FailurePolicy,
+    retry_policy: RetryPolicy,
+    key_format: KeyFormat,
+    serde: S,
+}
+
+impl<S: Send + Sync> InMemoryConsumer<S> {
+    /// Decodes payloads with `serde` instead.
+    pub fn with_serde<S2>(self, serde: S2) -> InMemoryConsumer<S2> {
+        InMemoryConsumer {
+            broker: self.broker,
+            group_id: self.group_id,
+            topic: self.topic,
+            failure_policy: self.failure_policy,
+            retry_policy: self.retry_policy,
+            key_format: self.key_format,
+            serde,
+        }
+    }
+
+    /// Replaces the default policy of dead-lettering to `<topic>.dlq`.
+    pub fn with_failure_policy(mut self, failure_policy: FailurePolicy) -> Self {
+        self.failure_policy = failure_policy;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// How the topic's keys are encoded; [`KeyFormat::String`] by default.
+    pub fn with_key_format(mut self, key_format: KeyFormat) -> Self {
+        self.key_format = key_format;
+        self
+    }
+
+    /// Handles the payload of every record that is already on the topic,
+    /// then returns how many were processed.
+    pub async fn consume_available<T, H>(&self, handler: &H) -> Result<usize, KafkaConsumerError>
+    where
+        T: Clone + Debug + Send + 'static,
+        H: MessageHandler<T>,
+        S: Serde<T>,
+    {
+        self.consume_available_keyed::<Unkeyed, T, _>(&IgnoreKey(handler))
+            .await
+    }
+
+    /// Like [`InMemoryConsumer::consume_available`], but hands `handler` each
+    /// record's key along with its payload.
+    pub async fn consume_available_keyed<K, V, H>(
+        &self,
+        handler: &H,
+    ) -> Result<usize, KafkaConsumerError>
+    where
+        K: MessageKey,
+        V: Clone + Debug + Send + 'static,
+        H: MessageHandler<(K, V)>,
+        S: Serde<V>,
+    {
+        self.consume_pending(handler, &CancellationToken::new())
+            .await
+    }
+
+    /// Handles uncommitted records until there are none left or `shutdown`
+    /// is cancelled.
+    async fn consume_pending<K, V, H>(
+        &self,
+        handler: &H,
+        shutdown: &CancellationToken,
+    ) -> Result<usize, KafkaConsumerError>
+    where
+        K: MessageKey,
+        V: Clone + Debug + Send + 'static,
+        H: MessageHandler<(K, V)>,
+        S: Serde<V>,
+    {
+        let mut processed = 0;
+        while let Some(record) = self.broker.next_uncommitted(&self.group_id, &self.topic) {
+            if handler.is_saturated() {
+                handler.ready().await;
+            }
+            self.process_record(handler, &record, shutdown).await?;
+            if shutdown.is_cancelled() {
+                break;
+            }
+            processed += 1;
+        }
+        Ok(processed)
+    }
+
+    /// A record still waiting for the schema registry when `shutdown` is
+    /// cancelled is left uncommitted.
+    async fn process_record<K, V, H>(
+        &self,
+        handler: &H,
+        record: &InMemoryRecord,
+        shutdown: &CancellationToken,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        K: MessageKey,
+        V: Clone + Debug + Send + 'static,
+        H: MessageHandler<(K, V)>,
+        S: Serde<V>,
+    {
+        let decoded = self
+            .retry_policy
+            .decode(shutdown, || async {
+                let key = record.decode_key::<K>(self.key_format)?;
+                let payload = record.decode_with(&self.serde).await?;
+                Ok((key, payload))
+            })
+            .await;
+        let outcome = match decoded {
+            Err(failure) if failure.is_retriable() => {
+                info!(
+                    "Leaving {}[{}]@{} uncommitted on shutdown: {}",
+                    record.topic, record.partition, record.offset, failure.reason
+                );
+                return Ok(());
+            }
+            Ok(message) => self.retry_policy.handle(handler, message).await,
+            Err(failure) => Err(failure),
+        };
+        if let Err(failure) = outcome {
+            error!(
+                "Unable to {} message at {}[{}]@{}: {}",
+                failure.kind, record.topic, record.partition, record.offset, failure.reason
+            );
+            match &self.failure_policy {
+                FailurePolicy::DeadLetter(dead_letter_topic) if !failure.is_retriable() => {
+                    self.dead_letter(dead_letter_topic, record, &failure)
+                }
+                _ => {
+                    return Err(KafkaConsumerError::Halted {
+                        topic: record.topic.clone(),
+                        partition: record.partition,
+                        offset: record.offset,
+                        failure,
+                    })
+                }
+            }
+        }
+        self.broker.commit(&self.group_id, record);
+        Ok(())
+    }
+
+    fn dead_letter(
+        &self,
+        dead_letter_topic: &str,
+        record: &InMemoryRecord,
+        failure: &MessageFailure,
+    ) {
+        let mut headers = record.headers.clone();
+        headers.extend(
+            [
+                (ORIGINAL_TOPIC_HEADER, record.topic.clone()),
+                (ORIGINAL_PARTITION_HEADER, record.partition.to_string()),
+                (ORIGINAL_OFFSET_HEADER, record.offset.to_string()),
+                (ERROR_KIND_HEADER, failure.kind.to_string()),
+                (ERROR_MESSAGE_HEADER, failure.reason.clone()),
+            ]
+            .map(|(key, value)| (key.to_owned(), value)),
+        );
+        self.broker.append(
+            dead_letter_topic,
+            record.key.clone(),
+            headers,
+            record.payload.clone(),
+        );
+        warn!(
+            "Dead-lettered {} message from {}[{}]@{} to {}: {}",
+            failure.kind,
+            record.topic,
+            record.partition,
+            record.offset,
+            dead_letter_topic,
+            failure.reason
+        );
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> Consumer for InMemoryConsumer<S> {
+    type Serde = S;
+
+    async fn consume_keyed_with<K, V, H>(
+        &self,
+        handler: &H,
+        shutdown: CancellationToken,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        K: MessageKey,
+        V: Clone + Debug + Send + 'static,
+        H: MessageHandler<(K, V)>,
+        S: Serde<V>,
+    {
+        let mut appended = self.broker.inner.appended.subscribe();
+        loop {
+            appended.borrow_and_update();
+            self.consume_pending(handler, &shutdown).await?;
+            tokio::select! {
+                biased;
+
+                _ = shutdown.cancelled() => break,
+                _ = appended.changed() => {}
+            }
+        }
+        info!("Stopping in-memory consumer for topic {}", self.topic);
+        Ok(())
+    }
+
+    /// Handles partitions one record at a time, oldest first.
+    async fn consume_keyed_partitioned<K, V, H>(
+        &self,
+        handler: &H,
+        shutdown: CancellationToken,
+    ) -> Result<(), KafkaConsumerError>
+    where
+        K: MessageKey,
+        V: Clone + Debug + Send + 'static,
+        H: MessageHandler<(K, V)>,
+        S: Serde<V>,
+    {
+        self.consume_keyed_with(handler, shutdown).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InMemoryBroker;
+    use crate::consumer::{Consumer, KafkaConsumerError};
+    use crate::dead_letter::{
+        FailureKind, FailurePolicy, ERROR_KIND_HEADER, ORIGINAL_TOPIC_HEADER,
+    };
+    use crate::format::{AvroSerde, JsonSerde};
+    use crate::handler::RetryPolicy;
+    use crate::key::KeyFormat;
+    use crate::mock_schema_registry::MockSchemaRegistry;
+    use crate::producer::{Producer, EVENT_ID_HEADER};
+    use apache_avro::AvroSchema;
+    use serde::{Deserialize, Serialize};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, AvroSchema)]
+    struct Custom {
+        value: String,
+    }
+
+    #[tokio::test]
+    async fn test_produce_and_consume() {
+        let broker = InMemoryBroker::new();
+        let producer = broker.producer("custom");
+        let payload = Custom {
+            value: "payload".to_string(),
+        };
+        let delivery_report = producer
+            .produce_with_headers(
+                "key".to_string(),
+                payload.clone(),
+                &[(EVENT_ID_HEADER, "event-1")],
+            )
+            .await
+            .unwrap();
+        let records = broker.records("custom");
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].partition, delivery_report.partition);
+        assert_eq!(records[0].header(EVENT_ID_HEADER), Some("event-1"));
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let consumer = broker.consumer("group", "custom");
+        assert_eq!(consumer.consume_available(&sender).await.unwrap(), 1);
+        assert_eq!(receiver.recv().await, Some(payload));
+        assert_eq!(
+            broker.committed_offset("group", "custom", delivery_report.partition),
+            Some(1)
+        );
+        // Committed records are not redelivered to the same group.
+        assert_eq!(consumer.consume_available(&sender).await.unwrap(), 0);
+        assert_eq!(
+            broker
+                .consumer("other-group", "custom")
+                .consume_available(&sender)
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_consume_until_shutdown() {
+        let broker = InMemoryBroker::with_default_partitions(2);
+        let producer = broker.producer("strings");
+        let consumer = broker.consumer("group", "strings");
+        let (sender, mut receiver) = mpsc::channel::<String>(4);
+        let shutdown = CancellationToken::new();
+        let handle = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { consumer.consume(sender, shutdown).await }
+        });
+
+        for index in 0..3 {
+            producer
+                .produce(format!("key-{}", index), format!("payload-{}", index))
+                .await
+                .unwrap();
+        }
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            received.push(receiver.recv().await.unwrap());
+        }
+        received.sort();
+        assert_eq!(received, vec!["payload-0", "payload-1", "payload-2"]);
+
+        shutdown.cancel();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failure_policies() {
+        let broker = InMemoryBroker::new();
+        broker
+            .producer("custom")
+            .produce("key".to_string(), "not a custom struct".to_string())
+            .await
+            .unwrap();
+        let (sender, _receiver) = mpsc::unbounded_channel::<Custom>();
+
+        let halted = broker
+            .consumer("halting", "custom")
+            .with_failure_policy(FailurePolicy::Halt)
+            .consume_available(&sender)
+            .await;
+        assert!(matches!(
+            halted,
+            Err(KafkaConsumerError::Halted { failure, .. }) if failure.kind == FailureKind::Deserialize
+        ));
+
+        let dead_lettered = broker
+            .consumer("dead-lettering", "custom")
+            .with_retry_policy(RetryPolicy {
+                max_retries: 0,
+                ..RetryPolicy::default()
+            })
+            .consume_available(&sender)
+            .await
+            .unwrap();
+        assert_eq!(dead_lettered, 1);
+        let dead_letters = broker.records("custom.dlq");
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(
+            dead_letters[0].header(ORIGINAL_TOPIC_HEADER),
+            Some("custom")
+        );
+        assert_eq!(
+            dead_letters[0].header(ERROR_KIND_HEADER),
+            Some("deserialize")
+        );
+        assert_eq!(
+            dead_letters[0].decode::<String>().unwrap(),
+            "not a custom struct"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_schema_registry_is_retried_until_it_recovers() {
+        let broker = InMemoryBroker::new();
+        let mut registry = MockSchemaRegistry::start().await;
+        let payload = Custom {
+            value: "payload".to_string(),
+        };
+        broker
+            .producer("custom")
+            .with_serde(AvroSerde::new(registry.url().to_owned()))
+            .produce("key".to_string(), payload.clone())
+            .await
+            .unwrap();
+        registry.stop().await;
+
+        let consumer = broker
+            .consumer("group", "custom")
+            .with_serde(AvroSerde::new(registry.url().to_owned()))
+            .with_failure_policy(FailurePolicy::DeadLetter("custom.dlq".to_string()))
+            .with_retry_policy(RetryPolicy {
+                max_retries: 0,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(50),
+            });
+        let (sender, mut receiver) = mpsc::channel::<Custom>(1);
+        let shutdown = CancellationToken::new();
+        let handle = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { consumer.consume(sender, shutdown).await }
+        });
+
+        // Well past the handler's retry budget, the record is still pending.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(receiver.try_recv().is_err());
+        assert!(!handle.is_finished());
+        assert!(broker.records("custom.dlq").is_empty());
+
+        registry.restart().await;
+        let received = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap();
+        assert_eq!(received, Some(payload));
+        shutdown.cancel();
+        handle.await.unwrap().unwrap();
+        assert!(broker.records("custom.dlq").is_empty());
+        assert_eq!(broker.committed_offset("group", "custom", 0), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_schema_registry_leaves_record_uncommitted_on_shutdown() {
+        let broker = InMemoryBroker::new();
+        let mut registry = MockSchemaRegistry::start().await;
+        broker
+            .producer("custom")
+            .with_serde(AvroSerde::new(registry.url().to_owned()))
+            .produce(
+                "key".to_string(),
+                Custom {
+                    value: "payload".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        registry.stop().await;
+
+        let consumer = broker
+            .consumer("group", "custom")
+            .with_serde(AvroSerde::new(registry.url().to_owned()))
+            .with_retry_policy(RetryPolicy {
+                initial_backoff: Duration::from_millis(10),
+                ..RetryPolicy::default()
+            });
+        let (sender, _receiver) = mpsc::channel::<Custom>(1);
+        let shutdown = CancellationToken::new();
+        let handle = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { consumer.consume(sender, shutdown).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown.cancel();
+        handle.await.unwrap().unwrap();
+        assert!(broker.records("custom.dlq").is_empty());
+        assert_eq!(broker.committed_offset("group", "custom", 0), None);
+    }
+
+    #[tokio::test]
+    async fn test_typed_keys() {
+        let broker = InMemoryBroker::new();
+        let producer = broker
+            .producer("accounts")
+            .with_key_format(KeyFormat::Integer);
+        producer.produce(7i64, "opened".to_string()).await.unwrap();
+        let records = broker.records("accounts");
+        assert_eq!(records[0].key.as_deref(), Some(&7i64.to_be_bytes()[..]));
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<(i64, String)>();
+        let consumed = broker
+            .consumer("group", "accounts")
+            .with_key_format(KeyFormat::Integer)
+            .consume_available_keyed(&sender)
+            .await
+            .unwrap();
+        assert_eq!(consumed, 1);
+        assert_eq!(receiver.recv().await, Some((7, "opened".to_string())));
+
+        // Consumers expecting a different key format fail to decode the key.
+        let halted = broker
+            .consumer("string-keys", "accounts")
+            .with_failure_policy(FailurePolicy::Halt)
+            .consume_available_keyed(&sender)
+            .await;
+        assert!(matches!(
+            halted,
+            Err(KafkaConsumerError::Halted { failure, .. }) if failure.kind == FailureKind::Decode
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_custom_serde() {
+        let broker = InMemoryBroker::new();
+        let payload = Custom {
+            value: "payload".to_string(),
+        };
+        broker
+            .producer("json")
+            .with_serde(JsonSerde::new())
+            .produce("key".to_string(), payload.clone())
+            .await
+            .unwrap();
+        let records = broker.records("json");
+        assert_eq!(records[0].payload, br#"{"value":"payload"}"#);
+        assert_eq!(
+            records[0]
+                .decode_with::<Custom, _>(&JsonSerde::new())
+                .await
+                .unwrap(),
+            payload
+        );
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Custom>();
+        let consumed = broker
+            .consumer("group", "json")
+            .with_serde(JsonSerde::new())
+            .consume_available(&sender)
+            .await
+            .unwrap();
+        assert_eq!(consumed, 1);
+        assert_eq!(receiver.recv().await, Some(payload));
+
+        // Consumers expecting the default Avro encoding fail to decode it.
+        let halted = broker
+            .consumer("avro", "json")
+            .with_failure_policy(FailurePolicy::Halt)
+            .consume_available(&sender)
+            .await;
+        assert!(matches!(
+            halted,
+            Err(KafkaConsumerError::Halted { failure, .. }) if failure.kind == FailureKind::Decode
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::{global, Context, KeyValue};
+use rdkafka::statistics::Statistics;
+use rdkafka::ClientContext;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::error;
+
+const METER_NAME: &str = "kafka";
+
+type PartitionLag = Arc<Mutex<HashMap<(String, i32), i64>>>;
+
+#[derive(Clone)]
+pub(crate) struct ProducerMetrics {
+    messages: Counter<u64>,
+    duration: Histogram<f64>,
+}
+
+impl ProducerMetrics {
+    pub(crate) fn new() -> Self {
+        let meter = global::meter(METER_NAME);
+        Self {
+            messages: meter
+                .u64_counter("kafka.producer.messages")
+                .with_description("Messages produced, by topic and outcome")
+                .init(),
+            duration: meter
+                .f64_histogram("kafka.producer.duration.seconds")
+                .with_description("Time from encoding to broker acknowledgement")
+                .init(),
+        }
+    }
+
+    pub(crate) fn record(&self, topic: &str, elapsed: Duration, success: bool) {
+        let attributes = [
+            KeyValue::new("topic", topic.to_owned()),
+            KeyValue::new("outcome", if success { "success" } else { "failure" }),
+        ];
+        let context = Context::current();
+        self.messages.add(&context, 1, &attributes);
+        self.duration
+            .record(&context, elapsed.as_secs_f64(), &attributes);
+    }
+}
+
+pub(crate) struct ConsumerMetrics {
+    messages: Counter<u64>,
+}
+
+impl ConsumerMetrics {
+    /// Also exports the lag collected by `stats_context` as an observable gauge.
+    pub(crate) fn new(stats_context: &ConsumerStatsContext) -> Self {
+        let meter = global::meter(METER_NAME);
+        let lag_gauge = meter
+            .i64_observable_gauge("kafka.consumer.lag")
+            .with_description("Messages between the committed offset and the high watermark")
+            .init();
+        let partition_lag = stats_context.partition_lag.clone();
+        if let Err(e) = meter.register_callback(move |context| {
+            if let Ok(partition_lag) = partition_lag.lock() {
+                for ((topic, partition), lag) in partition_lag.iter() {
+                    lag_gauge.observe(
+                        context,
+                        *lag,
+                        &[
+                            KeyValue::new("topic", topic.clone()),
+                            KeyValue::new("partition", i64::from(*partition)),
+                        ],
+                    );
+                }
+            }
+        }) {
+            error!("Unable to register consumer lag callback: {}", e);
+        }
+        Self {
+            messages: meter
+                .u64_counter("kafka.consumer.messages")
+                .with_description("Messages consumed, by topic and outcome")
+                .init(),
+        }
+    }
+
+    pub(crate) fn record(&self, topic: &str, success: bool) {
+        self.messages.add(
+            &Context::current(),
+            1,
+            &[
+                KeyValue::new("topic", topic.to_owned()),
+                KeyValue::new("outcome", if success { "consumed" } else { "failed" }),
+            ],
+        );
+    }
+}
+
+/// Keeps the per-partition consumer lag reported in librdkafka statistics.
+#[derive(Clone, Default)]
+pub struct ConsumerStatsContext {
+    partition_lag: PartitionLag,
+}
+
+impl ClientContext for ConsumerStatsContext {
+    /// Partitions missing from a report, such as those revoked on rebalance,
+    /// are zeroed rather than dropped: the exporter keeps reporting the last
+    /// observed value of a gauge, so a dropped partition would keep its lag.
+    fn stats(&self, statistics: Statistics) {
+        let Ok(mut partition_lag) = self.partition_lag.lock() else {
+            return;
+        };
+        partition_lag.values_mut().for_each(|lag| *lag = 0);
+        for (topic_name, topic) in statistics.topics {
+            // Partition -1 is librdkafka's internal unassigned partition, and
+            // partitions this consumer does not own report a lag of -1.
+            for (partition, stats) in topic.partitions.iter().filter(|(p, _)| **p >= 0) {
+                if stats.consumer_lag >= 0 {
+                    partition_lag.insert((topic_name.clone(), *partition), stats.consumer_lag);
+                }
+            }
+        }
+    }
+}
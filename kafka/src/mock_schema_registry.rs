@@ -0,0 +1,649 @@
+//! A Confluent-compatible schema registry served in-process on an ephemeral
+//! port, so the Avro encoding path and schema registration can be tested
+//! without docker.
+//!
+//! Schemas with references are not supported, and every schema is stored and
+//! returned as posted. Compatibility is checked for Avro schemas only, against
+//! the latest version of a subject rather than transitively.
+
+use apache_avro::schema_compatibility::SchemaCompatibility;
+use apache_avro::Schema;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Which registered versions a new schema must stay compatible with,
+/// following the schema registry's modes of the same name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompatibilityLevel {
+    None,
+    /// Readers using the new schema can read data written with the latest one.
+    #[default]
+    Backward,
+    /// Readers using the latest schema can read data written with the new one.
+    Forward,
+    /// Both backward and forward.
+    Full,
+}
+
+impl fmt::Display for CompatibilityLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CompatibilityLevel::None => "NONE",
+            CompatibilityLevel::Backward => "BACKWARD",
+            CompatibilityLevel::Forward => "FORWARD",
+            CompatibilityLevel::Full => "FULL",
+        })
+    }
+}
+
+/// A schema as registered, parsed when it is Avro.
+enum StoredSchema {
+    Avro { parsed: Schema, schema: String },
+    Other { schema_type: String, schema: String },
+}
+
+impl StoredSchema {
+    fn schema(&self) -> String {
+        match self {
+            StoredSchema::Avro { schema, .. } | StoredSchema::Other { schema, .. } => {
+                schema.clone()
+            }
+        }
+    }
+
+    /// `None` for Avro, which clients assume when no type is given.
+    fn schema_type(&self) -> Option<String> {
+        match self {
+            StoredSchema::Avro { .. } => None,
+            StoredSchema::Other { schema_type, .. } => Some(schema_type.clone()),
+        }
+    }
+
+    /// Schemas of other types are only required to keep their type.
+    fn is_compatible(&self, level: CompatibilityLevel, candidate: &StoredSchema) -> bool {
+        match (self, candidate) {
+            (
+                StoredSchema::Avro { parsed: latest, .. },
+                StoredSchema::Avro {
+                    parsed: candidate, ..
+                },
+            ) => is_compatible(level, latest, candidate),
+            (
+                StoredSchema::Other { schema_type, .. },
+                StoredSchema::Other {
+                    schema_type: candidate_type,
+                    ..
+                },
+            ) => schema_type == candidate_type,
+            _ => false,
+        }
+    }
+}
+
+/// Whether `candidate` may be registered after `latest` under `level`.
+fn is_compatible(level: CompatibilityLevel, latest: &Schema, candidate: &Schema) -> bool {
+    let latest = &without_logical_types(latest);
+    let candidate = &without_logical_types(candidate);
+    match level {
+        CompatibilityLevel::None => true,
+        CompatibilityLevel::Backward => SchemaCompatibility::can_read(latest, candidate),
+        CompatibilityLevel::Forward => SchemaCompatibility::can_read(candidate, latest),
+        CompatibilityLevel::Full => SchemaCompatibility::mutual_read(latest, candidate),
+    }
+}
+
+/// apache-avro's checker does not match logical types, not even against
+/// themselves, so they are compared by their underlying type alone.
+fn without_logical_types(schema: &Schema) -> Schema {
+    fn strip(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(object) => {
+                object.remove("logicalType");
+                object.values_mut().for_each(strip);
+            }
+            serde_json::Value::Array(values) => values.iter_mut().for_each(strip),
+            _ => {}
+        }
+    }
+    let Ok(mut value) = serde_json::to_value(schema) else {
+        return schema.clone();
+    };
+    strip(&mut value);
+    Schema::parse(&value).unwrap_or_else(|_| schema.clone())
+}
+
+#[derive(Default)]
+struct RegistryState {
+    compatibility_level: CompatibilityLevel,
+    /// Registered schemas; a schema's id is its index plus one.
+    schemas: Vec<StoredSchema>,
+    /// Schema ids per subject, in version order.
+    subjects: BTreeMap<String, Vec<u32>>,
+}
+
+impl RegistryState {
+    fn schema(&self, id: u32) -> Option<&StoredSchema> {
+        self.schemas.get(id.checked_sub(1)? as usize)
+    }
+
+    fn id_of(&self, schema: &StoredSchema) -> Option<u32> {
+        self.schemas
+            .iter()
+            .position(|registered| {
+                registered.schema_type() == schema.schema_type()
+                    && registered.schema() == schema.schema()
+            })
+            .map(|index| index as u32 + 1)
+    }
+
+    fn versions(&self, subject: &str) -> Result<&Vec<u32>, RegistryError> {
+        self.subjects
+            .get(subject)
+            .ok_or_else(|| RegistryError::SubjectNotFound(subject.to_owned()))
+    }
+
+    /// Resolves `latest`, `-1` or a 1-based version number to the version and schema id.
+    fn version(&self, subject: &str, version: &str) -> Result<(usize, u32), RegistryError> {
+        let versions = self.versions(subject)?;
+        let version = match version {
+            "latest" | "-1" => versions.len(),
+            number => number
+                .parse()
+                .map_err(|_| RegistryError::VersionNotFound(version.to_owned()))?,
+        };
+        version
+            .checked_sub(1)
+            .and_then(|index| versions.get(index))
+            .map(|id| (version, *id))
+            .ok_or_else(|| RegistryError::VersionNotFound(version.to_string()))
+    }
+}
+
+enum RegistryError {
+    SubjectNotFound(String),
+    VersionNotFound(String),
+    SchemaNotFound,
+    InvalidSchema(String),
+    Incompatible(CompatibilityLevel),
+}
+
+impl IntoResponse for RegistryError {
+    fn into_response(self) -> Response {
+        let (status, error_code, message) = match self {
+            RegistryError::SubjectNotFound(subject) => (
+                StatusCode::NOT_FOUND,
+                40401,
+                format!("Subject '{}' not found.", subject),
+            ),
+            RegistryError::VersionNotFound(version) => (
+                StatusCode::NOT_FOUND,
+                40402,
+                format!("Version {} not found.", version),
+            ),
+            RegistryError::SchemaNotFound => (
+                StatusCode::NOT_FOUND,
+                40403,
+                "Schema not found".to_owned(),
+            ),
+            RegistryError::InvalidSchema(reason) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                42201,
+                format!("Invalid schema: {}", reason),
+            ),
+            RegistryError::Incompatible(level) => (
+                StatusCode::CONFLICT,
+                409,
+                format!(
+                    "Schema being registered is incompatible with an earlier schema for compatibility level {}",
+                    level
+                ),
+            ),
+        };
+        (
+            status,
+            Json(json!({ "error_code": error_code, "message": message })),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SchemaRequest {
+    schema: String,
+    schema_type: Option<String>,
+}
+
+impl SchemaRequest {
+    fn parse(&self) -> Result<StoredSchema, RegistryError> {
+        match self.schema_type.as_deref() {
+            None | Some("AVRO") => Schema::parse_str(&self.schema)
+                .map(|parsed| StoredSchema::Avro {
+                    parsed,
+                    schema: self.schema.clone(),
+                })
+                .map_err(|e| RegistryError::InvalidSchema(e.to_string())),
+            Some(schema_type) => Ok(StoredSchema::Other {
+                schema_type: schema_type.to_owned(),
+                schema: self.schema.clone(),
+            }),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SubjectVersion {
+    subject: String,
+    version: usize,
+    id: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schema_type: Option<String>,
+    schema: String,
+}
+
+type SharedState = Arc<Mutex<RegistryState>>;
+
+/// A running mock registry; the server stops when this is dropped.
+pub struct MockSchemaRegistry {
+    addr: SocketAddr,
+    url: String,
+    state: SharedState,
+    server: Option<(oneshot::Sender<()>, JoinHandle<()>)>,
+}
+
+impl MockSchemaRegistry {
+    /// Starts a registry enforcing [`CompatibilityLevel::Backward`].
+    pub async fn start() -> Self {
+        Self::start_with(CompatibilityLevel::default()).await
+    }
+
+    pub async fn start_with(compatibility_level: CompatibilityLevel) -> Self {
+        let state = Arc::new(Mutex::new(RegistryState {
+            compatibility_level,
+            ..RegistryState::default()
+        }));
+        let (addr, server) = serve(SocketAddr::from(([127, 0, 0, 1], 0)), state.clone());
+        Self {
+            addr,
+            url: format!("http://{}", addr),
+            state,
+            server: Some(server),
+        }
+    }
+
+    /// Stops serving until [`MockSchemaRegistry::restart`], so clients see
+    /// the registry as unreachable.
+    pub async fn stop(&mut self) {
+        if let Some((shutdown, stopped)) = self.server.take() {
+            let _ = shutdown.send(());
+            let _ = stopped.await;
+        }
+    }
+
+    /// Serves again on the same address, keeping every registered schema.
+    pub async fn restart(&mut self) {
+        self.stop().await;
+        self.server = Some(serve(self.addr, self.state.clone()).1);
+    }
+
+    /// Base URL to hand to [`SrSettings`](schema_registry_converter::async_impl::schema_registry::SrSettings).
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn subjects(&self) -> Vec<String> {
+        self.state().subjects.keys().cloned().collect()
+    }
+
+    /// Every Avro version registered under `subject`, oldest first.
+    pub fn schemas(&self, subject: &str) -> Vec<Schema> {
+        let state = self.state();
+        state
+            .subjects
+            .get(subject)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| match state.schema(*id) {
+                Some(StoredSchema::Avro { parsed, .. }) => Some(parsed.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn state(&self) -> MutexGuard<'_, RegistryState> {
+        self.state
+            .lock()
+            .expect("mock schema registry lock poisoned")
+    }
+}
+
+impl Drop for MockSchemaRegistry {
+    fn drop(&mut self) {
+        if let Some((shutdown, _)) = self.server.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+/// Serves the registry API on `addr` until the returned sender fires.
+fn serve(
+    addr: SocketAddr,
+    state: SharedState,
+) -> (SocketAddr, (oneshot::Sender<()>, JoinHandle<()>)) {
+    let app = Router::new()
+        .route("/subjects", get(list_subjects))
+        .route("/subjects/:subject", post(lookup_schema))
+        .route(
+            "/subjects/:subject/versions",
+            get(list_versions).post(register_schema),
+        )
+        .route("/subjects/:subject/versions/:version", get(get_version))
+        .route("/schemas/ids/:id", get(get_schema_by_id))
+        .route(
+            "/compatibility/subjects/:subject/versions/:version",
+            post(check_compatibility),
+        )
+        .route("/config", get(get_config))
+        .with_state(state);
+    let server = axum::Server::bind(&addr).serve(app.into_make_service());
+    let addr = server.local_addr();
+    let (shutdown, stopped) = oneshot::channel();
+    let server = tokio::spawn(async move {
+        let _ = server
+            .with_graceful_shutdown(async {
+                let _ = stopped.await;
+            })
+            .await;
+    });
+    (addr, (shutdown, server))
+}
+
+fn lock(state: &SharedState) -> MutexGuard<'_, RegistryState> {
+    state.lock().expect("mock schema registry lock poisoned")
+}
+
+async fn list_subjects(State(state): State<SharedState>) -> Json<Vec<String>> {
+    Json(lock(&state).subjects.keys().cloned().collect())
+}
+
+async fn list_versions(
+    State(state): State<SharedState>,
+    Path(subject): Path<String>,
+) -> Result<Json<Vec<usize>>, RegistryError> {
+    let state = lock(&state);
+    Ok(Json((1..=state.versions(&subject)?.len()).collect()))
+}
+
+async fn get_version(
+    State(state): State<SharedState>,
+    Path((subject, version)): Path<(String, String)>,
+) -> Result<Json<SubjectVersion>, RegistryError> {
+    let state = lock(&state);
+    let (version, id) = state.version(&subject, &version)?;
+    let schema = state.schema(id).ok_or(RegistryError::SchemaNotFound)?;
+    Ok(Json(SubjectVersion {
+        subject,
+        version,
+        id,
+        schema_type: schema.schema_type(),
+        schema: schema.schema(),
+    }))
+}
+
+async fn register_schema(
+    State(state): State<SharedState>,
+    Path(subject): Path<String>,
+    Json(request): Json<SchemaRequest>,
+) -> Result<Json<serde_json::Value>, RegistryError> {
+    let schema = request.parse()?;
+    let mut state = lock(&state);
+    let existing_id = state.id_of(&schema);
+    let versions = state.subjects.get(&subject).cloned().unwrap_or_default();
+    if let Some(id) = existing_id.filter(|id| versions.contains(id)) {
+        return Ok(Json(json!({ "id": id })));
+    }
+    if let Some(latest) = versions.last().and_then(|id| state.schema(*id)) {
+        if !latest.is_compatible(state.compatibility_level, &schema) {
+            return Err(RegistryError::Incompatible(state.compatibility_level));
+        }
+    }
+    let id = match existing_id {
+        Some(id) => id,
+        None => {
+            state.schemas.push(schema);
+            state.schemas.len() as u32
+        }
+    };
+    state.subjects.entry(subject).or_default().push(id);
+    Ok(Json(json!({ "id": id })))
+}
+
+async fn lookup_schema(
+    State(state): State<SharedState>,
+    Path(subject): Path<String>,
+    Json(request): Json<SchemaRequest>,
+) -> Result<Json<SubjectVersion>, RegistryError> {
+    let schema = request.parse()?;
+    let state = lock(&state);
+    let id = state.id_of(&schema).ok_or(RegistryError::SchemaNotFound)?;
+    let version = state
+        .versions(&subject)?
+        .iter()
+        .position(|registered| *registered == id)
+        .ok_or(RegistryError::SchemaNotFound)?
+        + 1;
+    Ok(Json(SubjectVersion {
+        subject,
+        version,
+        id,
+        schema_type: schema.schema_type(),
+        schema: schema.schema(),
+    }))
+}
+
+async fn get_schema_by_id(
+    State(state): State<SharedState>,
+    Path(id): Path<u32>,
+) -> Result<Json<serde_json::Value>, RegistryError> {
+    let state = lock(&state);
+    let schema = state.schema(id).ok_or(RegistryError::SchemaNotFound)?;
+    Ok(Json(
+        json!({ "schema": schema.schema(), "schemaType": schema.schema_type() }),
+    ))
+}
+
+async fn check_compatibility(
+    State(state): State<SharedState>,
+    Path((subject, version)): Path<(String, String)>,
+    Json(request): Json<SchemaRequest>,
+) -> Result<Json<serde_json::Value>, RegistryError> {
+    let schema = request.parse()?;
+    let state = lock(&state);
+    let (_, id) = state.version(&subject, &version)?;
+    let registered = state.schema(id).ok_or(RegistryError::SchemaNotFound)?;
+    Ok(Json(json!({
+        "is_compatible": registered.is_compatible(state.compatibility_level, &schema)
+    })))
+}
+
+async fn get_config(State(state): State<SharedState>) -> Json<serde_json::Value> {
+    Json(json!({ "compatibilityLevel": lock(&state).compatibility_level.to_string() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompatibilityLevel, MockSchemaRegistry};
+    use crate::util::{check_schema_compatibility, register_schema, CompatibilityCheck};
+    use apache_avro::{from_value, AvroSchema, Schema};
+    use schema_registry_converter::async_impl::easy_avro::{EasyAvroDecoder, EasyAvroEncoder};
+    use schema_registry_converter::async_impl::schema_registry::SrSettings;
+    use schema_registry_converter::avro_common::get_supplied_schema;
+    use schema_registry_converter::schema_registry_common::SubjectNameStrategy;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, AvroSchema)]
+    struct Custom {
+        value: String,
+    }
+
+    #[tokio::test]
+    async fn test_encode_decode_roundtrip() {
+        let registry = MockSchemaRegistry::start().await;
+        let sr_settings = SrSettings::new(registry.url().to_owned());
+        let payload = Custom {
+            value: "payload".to_string(),
+        };
+        let strategy = SubjectNameStrategy::TopicNameStrategyWithSchema(
+            "custom".to_string(),
+            false,
+            get_supplied_schema(&Custom::get_schema()),
+        );
+        let encoded = EasyAvroEncoder::new(sr_settings.clone())
+            .encode_struct(payload.clone(), &strategy)
+            .await
+            .unwrap();
+        assert_eq!(registry.subjects(), vec!["custom-value".to_string()]);
+
+        let decoded = EasyAvroDecoder::new(sr_settings)
+            .decode(Some(&encoded))
+            .await
+            .unwrap();
+        assert_eq!(from_value::<Custom>(&decoded.value).unwrap(), payload);
+    }
+
+    #[tokio::test]
+    async fn test_registration_enforces_compatibility() {
+        let registry = MockSchemaRegistry::start().await;
+        let v1 = Custom::get_schema();
+        let v2 = Schema::parse_str(
+            r#"{"type":"record","name":"Custom","fields":[
+                {"name":"value","type":"string"},
+                {"name":"count","type":"int","default":0}]}"#,
+        )
+        .unwrap();
+        let breaking = Schema::parse_str(
+            r#"{"type":"record","name":"Custom","fields":[{"name":"value","type":"int"}]}"#,
+        )
+        .unwrap();
+
+        let first = register_schema(registry.url().to_owned(), "custom".to_string(), v1.clone())
+            .await
+            .unwrap();
+        let again = register_schema(registry.url().to_owned(), "custom".to_string(), v1)
+            .await
+            .unwrap();
+        assert_eq!(first.id, again.id);
+        register_schema(registry.url().to_owned(), "custom".to_string(), v2)
+            .await
+            .unwrap();
+        assert!(register_schema(
+            registry.url().to_owned(),
+            "custom".to_string(),
+            breaking.clone()
+        )
+        .await
+        .is_err());
+        assert_eq!(registry.schemas("custom").len(), 2);
+
+        // The registered schema keeps the default that made it compatible.
+        let latest: serde_json::Value = serde_json::from_str(
+            &reqwest::get(format!(
+                "{}/subjects/custom/versions/latest",
+                registry.url()
+            ))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap(),
+        )
+        .unwrap();
+        let latest = Schema::parse_str(latest["schema"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&latest).unwrap()["fields"][1]["default"],
+            0
+        );
+
+        let lenient = MockSchemaRegistry::start_with(CompatibilityLevel::None).await;
+        register_schema(
+            lenient.url().to_owned(),
+            "custom".to_string(),
+            Custom::get_schema(),
+        )
+        .await
+        .unwrap();
+        register_schema(lenient.url().to_owned(), "custom".to_string(), breaking)
+            .await
+            .unwrap();
+        assert_eq!(lenient.schemas("custom").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_check_schema_compatibility() {
+        let with_default = Schema::parse_str(
+            r#"{"type":"record","name":"Custom","fields":[
+                {"name":"value","type":"string"},
+                {"name":"count","type":"int","default":0}]}"#,
+        )
+        .unwrap();
+        let without_default = Schema::parse_str(
+            r#"{"type":"record","name":"Custom","fields":[
+                {"name":"value","type":"string"},
+                {"name":"count","type":"int"}]}"#,
+        )
+        .unwrap();
+        let check = |registry: &MockSchemaRegistry, schema: Schema| {
+            let url = registry.url().to_owned();
+            async move {
+                check_schema_compatibility(&url, "custom-value", &schema)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let backward = MockSchemaRegistry::start().await;
+        assert_eq!(
+            check(&backward, Custom::get_schema()).await,
+            CompatibilityCheck::NewSubject
+        );
+        register_schema(
+            backward.url().to_owned(),
+            "custom-value".to_string(),
+            Custom::get_schema(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            check(&backward, with_default.clone()).await,
+            CompatibilityCheck::Compatible
+        );
+        assert_eq!(
+            check(&backward, without_default.clone()).await,
+            CompatibilityCheck::Incompatible
+        );
+
+        let forward = MockSchemaRegistry::start_with(CompatibilityLevel::Forward).await;
+        register_schema(
+            forward.url().to_owned(),
+            "custom-value".to_string(),
+            Custom::get_schema(),
+        )
+        .await
+        .unwrap();
+        assert!(check(&forward, without_default).await.is_compatible());
+    }
+}
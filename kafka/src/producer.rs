@@ -1,59 +1,362 @@
+use crate::format::{AvroSerde, Serde};
+use crate::key::{encode_key, is_unkeyed, KeyError, KeyFormat, MessageKey};
+use crate::metrics::ProducerMetrics;
 use crate::util;
-use apache_avro::AvroSchema;
+use async_trait::async_trait;
+use derive_builder::Builder;
 use opentelemetry::trace::{Span, TraceContextExt, Tracer};
 use opentelemetry::{global, Context, Key, KeyValue, StringValue};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
 use rdkafka::message::{Header, OwnedHeaders};
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer as _};
 use rdkafka::ClientConfig;
-use schema_registry_converter::async_impl::easy_avro::EasyAvroEncoder;
-use schema_registry_converter::async_impl::schema_registry::SrSettings;
-use schema_registry_converter::avro_common::get_supplied_schema;
-use schema_registry_converter::schema_registry_common::SubjectNameStrategy;
-use serde::Serialize;
-use std::sync::Arc;
-use std::time::Duration;
+use schema_registry_converter::error::SRCError;
+use std::fmt;
+use std::time::{Duration, Instant};
+use thiserror::Error;
 use tracing::{error, info};
 
+pub const EVENT_ID_HEADER: &str = "event.id";
+pub const CORRELATION_ID_HEADER: &str = "correlation.id";
+
+#[derive(Error, Debug)]
+pub enum KafkaProducerError {
+    #[error("Avro encoding error: {0}")]
+    Encoding(#[from] apache_avro::Error),
+
+    #[error("JSON encoding error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Schema registry error: {0}")]
+    SchemaRegistry(#[from] SRCError),
+
+    #[error("Key encoding error: {0}")]
+    Key(#[from] KeyError),
+
+    #[error("Delivery timed out")]
+    DeliveryTimeout,
+
+    #[error("Producer queue full")]
+    QueueFull,
+
+    #[error("Broker rejected message: {0}")]
+    BrokerRejected(KafkaError),
+
+    #[error("Transaction error: {0}")]
+    Transaction(KafkaError),
+}
+
+impl KafkaProducerError {
+    /// Whether producing the same message again may succeed.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            KafkaProducerError::Encoding(_)
+            | KafkaProducerError::Json(_)
+            | KafkaProducerError::Key(_) => false,
+            KafkaProducerError::SchemaRegistry(e) => e.retriable,
+            KafkaProducerError::DeliveryTimeout | KafkaProducerError::QueueFull => true,
+            KafkaProducerError::BrokerRejected(e) => matches!(
+                e.rdkafka_error_code(),
+                Some(
+                    RDKafkaErrorCode::BrokerTransportFailure
+                        | RDKafkaErrorCode::AllBrokersDown
+                        | RDKafkaErrorCode::LeaderNotAvailable
+                        | RDKafkaErrorCode::NotLeaderForPartition
+                        | RDKafkaErrorCode::RequestTimedOut
+                        | RDKafkaErrorCode::NetworkException
+                        | RDKafkaErrorCode::NotEnoughReplicas
+                )
+            ),
+            KafkaProducerError::Transaction(KafkaError::Transaction(e)) => e.is_retriable(),
+            KafkaProducerError::Transaction(_) => false,
+        }
+    }
+
+    /// Whether the current transaction can no longer commit and must be aborted.
+    pub fn requires_abort(&self) -> bool {
+        matches!(
+            self,
+            KafkaProducerError::Transaction(KafkaError::Transaction(e)) if e.txn_requires_abort()
+        )
+    }
+}
+
+impl From<KafkaError> for KafkaProducerError {
+    fn from(error: KafkaError) -> Self {
+        if let KafkaError::Transaction(_) = error {
+            return KafkaProducerError::Transaction(error);
+        }
+        match error.rdkafka_error_code() {
+            Some(RDKafkaErrorCode::MessageTimedOut) => KafkaProducerError::DeliveryTimeout,
+            Some(RDKafkaErrorCode::QueueFull) => KafkaProducerError::QueueFull,
+            _ => KafkaProducerError::BrokerRejected(error),
+        }
+    }
+}
+
+/// Where a produced message ended up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryReport {
+    pub partition: i32,
+    pub offset: i64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Snappy => "snappy",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+/// Client settings for [`KafkaProducer::from_config`].
+#[derive(Builder, Clone, Debug)]
+#[builder(setter(into))]
+pub struct KafkaProducerConfig {
+    pub bootstrap_servers: String,
+    pub schema_registry_url: String,
+    pub topic: String,
+    #[builder(default)]
+    pub key_format: KeyFormat,
+    #[builder(default = "Duration::from_secs(5)")]
+    pub message_timeout: Duration,
+    /// Enables `enable.idempotence` with `acks=all`, so retries cannot
+    /// duplicate or reorder messages within a partition.
+    #[builder(default)]
+    pub idempotent: bool,
+    /// Makes the producer transactional, which also makes it idempotent.
+    #[builder(default)]
+    pub transactional_id: Option<String>,
+    #[builder(default = "Duration::from_secs(60)")]
+    pub transaction_timeout: Duration,
+    #[builder(default)]
+    pub compression: Compression,
+    #[builder(default = "Duration::from_millis(5)")]
+    pub linger: Duration,
+    /// Maximum size of a batch in bytes; librdkafka's default when unset.
+    #[builder(default)]
+    pub batch_size: Option<usize>,
+    #[builder(default = "100_000")]
+    pub queue_buffering_max_messages: usize,
+}
+
+impl KafkaProducerConfig {
+    pub(crate) fn client_config(&self) -> ClientConfig {
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", &self.bootstrap_servers)
+            .set("produce.offset.report", "true")
+            .set(
+                "message.timeout.ms",
+                self.message_timeout.as_millis().to_string(),
+            )
+            .set(
+                "queue.buffering.max.messages",
+                self.queue_buffering_max_messages.to_string(),
+            )
+            .set("compression.type", self.compression.to_string())
+            .set("linger.ms", self.linger.as_millis().to_string());
+        if let Some(batch_size) = self.batch_size {
+            client_config.set("batch.size", batch_size.to_string());
+        }
+        if self.idempotent || self.transactional_id.is_some() {
+            client_config
+                .set("enable.idempotence", "true")
+                .set("acks", "all");
+        }
+        if let Some(transactional_id) = &self.transactional_id {
+            client_config.set("transactional.id", transactional_id).set(
+                "transaction.timeout.ms",
+                self.transaction_timeout.as_millis().to_string(),
+            );
+        }
+        client_config
+    }
+}
+
+/// Publishing operations shared by [`KafkaProducer`] and
+/// `memory::InMemoryProducer` (behind the `test-util` feature).
+#[async_trait]
+pub trait Producer: Clone + Send + Sync + 'static {
+    /// How payloads are encoded.
+    type Serde: Send + Sync;
+
+    /// Produces `payload` with `headers` added to the record. The key is
+    /// encoded in the producer's [`KeyFormat`]; [`Unkeyed`](crate::key::Unkeyed)
+    /// produces a record without one.
+    async fn produce_with_headers<K: MessageKey, T: Send + 'static>(
+        &self,
+        key: K,
+        payload: T,
+        headers: &[(&str, &str)],
+    ) -> Result<DeliveryReport, KafkaProducerError>
+    where
+        Self::Serde: Serde<T>;
+
+    /// A producer for another topic sharing this one's client, key format and serde.
+    fn for_topic(&self, topic: impl Into<String>) -> Self;
+
+    fn with_key_format(self, key_format: KeyFormat) -> Self;
+
+    /// Blocks until every queued message is delivered or `timeout` elapses.
+    fn flush(&self, timeout: Duration) -> Result<(), KafkaProducerError>;
+
+    async fn produce<K: MessageKey, T: Send + 'static>(
+        &self,
+        key: K,
+        payload: T,
+    ) -> Result<DeliveryReport, KafkaProducerError>
+    where
+        Self::Serde: Serde<T>,
+    {
+        self.produce_with_headers(key, payload, &[]).await
+    }
+}
+
+/// Produces to one topic, encoding payloads with `S`. Avro keys are always
+/// encoded with the producer's [`AvroSerde`].
 #[derive(Clone)]
-pub struct KafkaProducer {
+pub struct KafkaProducer<S = AvroSerde> {
     producer: FutureProducer,
-    avro_encoder: Arc<EasyAvroEncoder>,
+    avro_serde: AvroSerde,
+    serde: S,
     topic: String,
+    key_format: KeyFormat,
+    metrics: ProducerMetrics,
+}
+
+#[async_trait]
+impl<S: Clone + Send + Sync + 'static> Producer for KafkaProducer<S> {
+    type Serde = S;
+
+    async fn produce_with_headers<K: MessageKey, T: Send + 'static>(
+        &self,
+        key: K,
+        payload: T,
+        headers: &[(&str, &str)],
+    ) -> Result<DeliveryReport, KafkaProducerError>
+    where
+        S: Serde<T>,
+    {
+        let started = Instant::now();
+        let result = self.encode_and_send(key, payload, headers).await;
+        self.metrics
+            .record(&self.topic, started.elapsed(), result.is_ok());
+        result
+    }
+
+    /// Shares the client, so both producers take part in the same transaction.
+    fn for_topic(&self, topic: impl Into<String>) -> Self {
+        Self {
+            topic: topic.into(),
+            ..self.clone()
+        }
+    }
+
+    fn with_key_format(mut self, key_format: KeyFormat) -> Self {
+        self.key_format = key_format;
+        self
+    }
+
+    fn flush(&self, timeout: Duration) -> Result<(), KafkaProducerError> {
+        Ok(self.producer.flush(timeout)?)
+    }
 }
 
 impl KafkaProducer {
     pub fn new(bootstrap_servers: String, schema_registry_url: String, topic: String) -> Self {
-        let producer: FutureProducer = ClientConfig::new()
-            .set("bootstrap.servers", bootstrap_servers)
-            .set("produce.offset.report", "true")
-            .set("message.timeout.ms", "5000")
-            .set("queue.buffering.max.messages", "10")
-            .create()
-            .expect("Producer creation error");
-        let sr_settings = SrSettings::new(schema_registry_url);
-        let avro_encoder = EasyAvroEncoder::new(sr_settings);
-        Self {
+        let config = KafkaProducerConfigBuilder::default()
+            .bootstrap_servers(bootstrap_servers)
+            .schema_registry_url(schema_registry_url)
+            .topic(topic)
+            .build()
+            .expect("Producer config error");
+        Self::from_config(&config).expect("Producer creation error")
+    }
+
+    /// Creates the producer and, for a transactional one, registers its
+    /// transactional id with the brokers. That registration blocks for up to
+    /// the transaction timeout.
+    pub fn from_config(config: &KafkaProducerConfig) -> Result<Self, KafkaProducerError> {
+        let producer: FutureProducer = config.client_config().create()?;
+        if config.transactional_id.is_some() {
+            producer.init_transactions(config.transaction_timeout)?;
+        }
+        let avro_serde = AvroSerde::new(config.schema_registry_url.clone());
+        Ok(Self {
             producer,
-            topic,
-            avro_encoder: Arc::new(avro_encoder),
+            topic: config.topic.clone(),
+            key_format: config.key_format,
+            serde: avro_serde.clone(),
+            avro_serde,
+            metrics: ProducerMetrics::new(),
+        })
+    }
+}
+
+impl<S> KafkaProducer<S> {
+    /// Encodes payloads with `serde` instead, sharing this producer's client.
+    pub fn with_serde<S2>(self, serde: S2) -> KafkaProducer<S2> {
+        KafkaProducer {
+            producer: self.producer,
+            avro_serde: self.avro_serde,
+            serde,
+            topic: self.topic,
+            key_format: self.key_format,
+            metrics: self.metrics,
         }
     }
 
-    pub async fn produce<T: Serialize + AvroSchema>(&self, key: String, payload: T) -> bool {
-        let value_strategy = SubjectNameStrategy::TopicNameStrategyWithSchema(
-            self.topic.clone(),
-            true,
-            get_supplied_schema(&T::get_schema()),
-        );
-        let payload = match self
-            .avro_encoder
-            .clone()
-            .encode_struct(payload, &value_strategy)
-            .await
-        {
-            Ok(v) => v,
-            Err(e) => panic!("Error getting payload: {}", e),
-        };
+    /// Starts a transaction; everything produced through this client until
+    /// [`commit_transaction`](Self::commit_transaction) is published atomically.
+    pub fn begin_transaction(&self) -> Result<(), KafkaProducerError> {
+        Ok(self.producer.begin_transaction()?)
+    }
+
+    /// Flushes and commits the current transaction. This blocks for up to `timeout`.
+    pub fn commit_transaction(&self, timeout: Duration) -> Result<(), KafkaProducerError> {
+        Ok(self.producer.commit_transaction(timeout)?)
+    }
+
+    /// Discards everything produced in the current transaction. This blocks
+    /// for up to `timeout`.
+    pub fn abort_transaction(&self, timeout: Duration) -> Result<(), KafkaProducerError> {
+        Ok(self.producer.abort_transaction(timeout)?)
+    }
+
+    /// Fetches cluster metadata from the brokers to prove they are reachable.
+    /// No topic is named, as brokers that auto-create topics would create it.
+    /// This blocks for up to `timeout`.
+    pub fn fetch_metadata(&self, timeout: Duration) -> Result<(), KafkaProducerError> {
+        self.producer.client().fetch_metadata(None, timeout)?;
+        Ok(())
+    }
+
+    async fn encode_and_send<K: MessageKey, T: Send>(
+        &self,
+        key: K,
+        payload: T,
+        extra_headers: &[(&str, &str)],
+    ) -> Result<DeliveryReport, KafkaProducerError>
+    where
+        S: Serde<T>,
+    {
+        let payload = self.serde.serialize(&self.topic, payload).await?;
+        let key = self.encode_key(key).await?;
         let mut span = global::tracer("producer").start("produce_to_kafka");
         span.set_attribute(KeyValue {
             key: Key::new("topic"),
@@ -70,22 +373,47 @@ impl KafkaProducer {
             key: "key",
             value: Some("value"),
         });
+        for (key, value) in extra_headers {
+            headers = headers.insert(Header {
+                key,
+                value: Some(*value),
+            });
+        }
         global::get_text_map_propagator(|propagator| {
             propagator.inject_context(&context, &mut util::HeaderInjector(&mut headers))
         });
 
-        let record = FutureRecord::to(&self.topic)
+        let mut record = FutureRecord::to(&self.topic)
             .payload(&payload)
-            .key(&key)
             .headers(headers);
+        if let Some(key) = &key {
+            record = record.key(key);
+        }
+
+        match self.producer.send(record, Duration::from_secs(5)).await {
+            Ok((partition, offset)) => {
+                info!(
+                    "message delivered to partition {} at offset {}",
+                    partition, offset
+                );
+                Ok(DeliveryReport { partition, offset })
+            }
+            Err((e, _)) => {
+                error!("{}", e);
+                Err(e.into())
+            }
+        }
+    }
 
-        let delivery_status = self.producer.send(record, Duration::from_secs(5)).await;
-        if delivery_status.is_err() {
-            error!("{}", delivery_status.err().unwrap().0.to_string());
-            return false;
-        } else {
-            info!("message delivered");
-            return true;
+    /// Avro keys are registered under the topic's key subject, like values
+    /// are under its value subject.
+    async fn encode_key<K: MessageKey>(
+        &self,
+        key: K,
+    ) -> Result<Option<Vec<u8>>, KafkaProducerError> {
+        if self.key_format != KeyFormat::Avro || is_unkeyed::<K>() {
+            return Ok(encode_key(self.key_format, &key)?);
         }
+        Ok(Some(self.avro_serde.encode(&self.topic, true, key).await?))
     }
 }
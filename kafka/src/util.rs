@@ -7,6 +7,11 @@ use schema_registry_converter::{
     error::SRCError,
     schema_registry_common::{RegisteredSchema, SuppliedSchema},
 };
+use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
+use thiserror::Error;
+
 pub struct HeaderInjector<'a>(pub &'a mut OwnedHeaders);
 
 impl<'a> Injector for HeaderInjector<'a> {
@@ -47,12 +52,131 @@ impl<'a> Extractor for HeaderExtractor<'a> {
     }
 }
 
+/// Succeeds when the schema registry answers `GET /subjects` with a success status.
+pub async fn check_schema_registry(
+    schema_registry_url: &str,
+    timeout: Duration,
+) -> Result<(), reqwest::Error> {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .build()?
+        .get(format!(
+            "{}/subjects",
+            schema_registry_url.trim_end_matches('/')
+        ))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// The schema as the registry should store it. Unlike the canonical form,
+/// the full JSON keeps field defaults, which compatibility checks rely on.
+pub fn supplied_schema(schema: &Schema) -> Result<Box<SuppliedSchema>, serde_json::Error> {
+    let mut supplied = get_supplied_schema(schema);
+    supplied.schema = serde_json::to_string(schema)?;
+    Ok(supplied)
+}
+
 pub async fn register_schema(
     schema_registry_url: String,
     subject: String,
     schema: Schema,
 ) -> Result<RegisteredSchema, SRCError> {
     let sr_settings = SrSettings::new(schema_registry_url);
-    let supplied_schema: SuppliedSchema = *get_supplied_schema(&schema);
-    post_schema(&sr_settings, subject, supplied_schema).await
+    let supplied_schema = supplied_schema(&schema)
+        .map_err(|e| SRCError::non_retryable_with_cause(e, "Could not serialize schema"))?;
+    post_schema(&sr_settings, subject, *supplied_schema).await
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompatibilityCheck {
+    /// Nothing is registered under the subject yet.
+    NewSubject,
+    /// The latest registered version can be followed by the schema.
+    Compatible,
+    Incompatible,
+}
+
+impl CompatibilityCheck {
+    pub fn is_compatible(&self) -> bool {
+        !matches!(self, CompatibilityCheck::Incompatible)
+    }
+}
+
+impl fmt::Display for CompatibilityCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompatibilityCheck::NewSubject => write!(f, "new subject"),
+            CompatibilityCheck::Compatible => write!(f, "compatible with the latest version"),
+            CompatibilityCheck::Incompatible => {
+                write!(f, "incompatible with the latest version")
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CompatibilityCheckError {
+    #[error("Schema registry request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("Schema registry responded with {status}: {body}")]
+    Registry { status: u16, body: String },
+
+    #[error("Unexpected schema registry response: {0}")]
+    Response(#[from] serde_json::Error),
+}
+
+#[derive(Deserialize)]
+struct CompatibilityResponse {
+    is_compatible: bool,
+}
+
+#[derive(Deserialize)]
+struct RegistryErrorBody {
+    error_code: u32,
+}
+
+const SUBJECT_NOT_FOUND: u32 = 40401;
+
+/// Asks the registry whether `schema` may follow the latest version registered
+/// under `subject`, judged under the compatibility mode configured for it.
+pub async fn check_schema_compatibility(
+    schema_registry_url: &str,
+    subject: &str,
+    schema: &Schema,
+) -> Result<CompatibilityCheck, CompatibilityCheckError> {
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/compatibility/subjects/{}/versions/latest",
+            schema_registry_url.trim_end_matches('/'),
+            subject
+        ))
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            "application/vnd.schemaregistry.v1+json",
+        )
+        .body(serde_json::json!({ "schema": serde_json::to_string(schema)? }).to_string())
+        .send()
+        .await?;
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        return match serde_json::from_str::<RegistryErrorBody>(&body) {
+            Ok(error) if error.error_code == SUBJECT_NOT_FOUND => {
+                Ok(CompatibilityCheck::NewSubject)
+            }
+            _ => Err(CompatibilityCheckError::Registry {
+                status: status.as_u16(),
+                body,
+            }),
+        };
+    }
+    let response: CompatibilityResponse = serde_json::from_str(&body)?;
+    Ok(if response.is_compatible {
+        CompatibilityCheck::Compatible
+    } else {
+        CompatibilityCheck::Incompatible
+    })
 }
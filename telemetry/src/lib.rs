@@ -0,0 +1,205 @@
+use common::settings::{LogFormat, TelemetrySettings, TraceExporter};
+use derive_builder::Builder;
+use opentelemetry::global;
+use opentelemetry::sdk::propagation::{TextMapCompositePropagator, TraceContextPropagator};
+use opentelemetry::sdk::trace::{self, Sampler, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::TraceError;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use std::net::SocketAddr;
+use thiserror::Error;
+use tracing_subscriber::filter::ParseError;
+use tracing_subscriber::util::TryInitError;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+#[derive(Builder, Clone, Debug)]
+#[builder(setter(into))]
+pub struct TelemetryConfig {
+    service_name: String,
+    /// Reported to Zipkin as the local endpoint of every span.
+    #[builder(default)]
+    service_address: Option<SocketAddr>,
+    #[builder(default = "TraceExporter::Disabled")]
+    exporter: TraceExporter,
+    /// Defaults to the exporter's [`default_endpoint`].
+    #[builder(default)]
+    endpoint: Option<String>,
+    #[builder(default = "1.0")]
+    sampling_ratio: f64,
+    #[builder(default = "\"info\".to_owned()")]
+    log_level: String,
+    #[builder(default = "LogFormat::Json")]
+    log_format: LogFormat,
+}
+
+impl TelemetryConfig {
+    pub fn from_settings(settings: &TelemetrySettings, service_address: SocketAddr) -> Self {
+        Self {
+            service_name: settings.service_name.clone(),
+            service_address: Some(service_address),
+            exporter: settings.exporter,
+            endpoint: settings.endpoint.clone(),
+            sampling_ratio: settings.sampling_ratio,
+            log_level: settings.log_level.clone(),
+            log_format: settings.log_format,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TelemetryError {
+    #[error("Unable to install trace exporter: {0}")]
+    Trace(#[from] TraceError),
+
+    #[error("Invalid log level filter: {0}")]
+    LevelFilter(#[from] ParseError),
+
+    #[error("Unable to install tracing subscriber: {0}")]
+    Subscriber(#[from] TryInitError),
+}
+
+/// Where `exporter` sends spans when no endpoint is configured: the local
+/// collector on the port its protocol conventionally uses.
+pub fn default_endpoint(exporter: TraceExporter) -> Option<&'static str> {
+    match exporter {
+        TraceExporter::Zipkin => Some("http://localhost:9411/api/v2/spans"),
+        TraceExporter::OtlpGrpc => Some("http://localhost:4317"),
+        TraceExporter::OtlpHttp => Some("http://localhost:4318/v1/traces"),
+        TraceExporter::Stdout | TraceExporter::Disabled => None,
+    }
+}
+
+/// Flushes buffered spans and shuts the tracer provider down when dropped.
+/// Keep it alive for the lifetime of `main`.
+#[must_use = "telemetry is shut down as soon as the guard is dropped"]
+pub struct TelemetryGuard {
+    _private: (),
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        global::shutdown_tracer_provider();
+    }
+}
+
+/// Installs the global propagator, tracer provider and tracing subscriber.
+pub fn init(config: TelemetryConfig) -> Result<TelemetryGuard, TelemetryError> {
+    // Accept and emit both W3C and B3 headers so services using either exporter interoperate.
+    global::set_text_map_propagator(TextMapCompositePropagator::new(vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(opentelemetry_zipkin::Propagator::new()),
+    ]));
+    let tracer = install_tracer(&config)?;
+
+    let (json, pretty) = match config.log_format {
+        LogFormat::Json => (Some(tracing_subscriber::fmt::layer().json()), None),
+        LogFormat::Pretty => (None, Some(tracing_subscriber::fmt::layer().pretty())),
+    };
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_new(&config.log_level)?)
+        .with(json)
+        .with(pretty)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .try_init()?;
+    Ok(TelemetryGuard { _private: () })
+}
+
+fn install_tracer(config: &TelemetryConfig) -> Result<Option<Tracer>, TraceError> {
+    let trace_config = trace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sampling_ratio,
+        ))))
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )]));
+    let endpoint = config
+        .endpoint
+        .clone()
+        .or_else(|| default_endpoint(config.exporter).map(str::to_owned));
+    let tracer = match config.exporter {
+        TraceExporter::Zipkin => {
+            let mut pipeline = opentelemetry_zipkin::new_pipeline()
+                .with_service_name(config.service_name.clone())
+                .with_trace_config(trace_config);
+            if let Some(service_address) = config.service_address {
+                pipeline = pipeline.with_service_address(service_address);
+            }
+            if let Some(endpoint) = endpoint {
+                pipeline = pipeline.with_collector_endpoint(endpoint);
+            }
+            pipeline.install_batch(opentelemetry::runtime::Tokio)?
+        }
+        TraceExporter::OtlpGrpc => {
+            let mut exporter = opentelemetry_otlp::new_exporter().tonic();
+            if let Some(endpoint) = endpoint {
+                exporter = exporter.with_endpoint(endpoint);
+            }
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(exporter)
+                .with_trace_config(trace_config)
+                .install_batch(opentelemetry::runtime::Tokio)?
+        }
+        TraceExporter::OtlpHttp => {
+            let mut exporter = opentelemetry_otlp::new_exporter().http();
+            if let Some(endpoint) = endpoint {
+                exporter = exporter.with_endpoint(endpoint);
+            }
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(exporter)
+                .with_trace_config(trace_config)
+                .install_batch(opentelemetry::runtime::Tokio)?
+        }
+        TraceExporter::Stdout => opentelemetry::sdk::export::trace::stdout::new_pipeline()
+            .with_trace_config(trace_config)
+            .install_simple(),
+        TraceExporter::Disabled => return Ok(None),
+    };
+    Ok(Some(tracer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_endpoint, install_tracer, TelemetryConfig, TelemetryConfigBuilder};
+    use common::settings::{LogFormat, Settings, TraceExporter};
+
+    #[test]
+    fn test_config_from_settings() {
+        let settings = Settings::defaults("books_api");
+        let config =
+            TelemetryConfig::from_settings(&settings.telemetry, settings.http.bind_address());
+        assert_eq!(config.exporter, TraceExporter::Zipkin);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.sampling_ratio, 1.0);
+        assert_eq!(config.endpoint, None);
+    }
+
+    #[test]
+    fn test_default_endpoint_follows_exporter() {
+        assert_eq!(
+            default_endpoint(TraceExporter::Zipkin),
+            Some("http://localhost:9411/api/v2/spans")
+        );
+        assert_eq!(
+            default_endpoint(TraceExporter::OtlpGrpc),
+            Some("http://localhost:4317")
+        );
+        assert_eq!(
+            default_endpoint(TraceExporter::OtlpHttp),
+            Some("http://localhost:4318/v1/traces")
+        );
+        assert_eq!(default_endpoint(TraceExporter::Stdout), None);
+    }
+
+    #[test]
+    fn test_disabled_exporter_installs_no_tracer() {
+        let config = TelemetryConfigBuilder::default()
+            .service_name("books_api")
+            .build()
+            .unwrap();
+        assert!(install_tracer(&config).unwrap().is_none());
+    }
+}